@@ -0,0 +1,79 @@
+/*!
+This is a simple SOCKS server example, serving SOCKS4, SOCKS4a and SOCKS5 clients on the same port.
+
+# Usage
+
+## HTTPie
+
+You can use HTTPie with `--proxy` flag to do an HTTP request through the SOCKS server, using any of
+the supported versions.
+
+```bash
+http --proxy=http:socks4://localhost:1080 http://example.com
+http --proxy=http:socks5://localhost:1080 http://example.com
+```
+*/
+
+use std::io::Error;
+
+use ::socks::{
+    async_trait,
+    common::{Context, Decision},
+    v4,
+    v5::{
+        self,
+        client::{Greeting, Request},
+        server::Choice,
+    },
+};
+use socks::socks;
+use tracing::{info, Level};
+use tracing_subscriber::FmtSubscriber;
+
+struct Example;
+
+impl Example {
+    fn new() -> Self {
+        Example
+    }
+}
+
+#[async_trait]
+impl v4::socks::Handler for Example {
+    async fn request(
+        &self,
+        _: &Context,
+        _: v4::client::Request,
+    ) -> Result<Decision<v4::Reply>, Error> {
+        Ok(Decision::Grant)
+    }
+}
+
+#[async_trait]
+impl v5::socks::Handler for Example {
+    async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> {
+        Ok(Choice {
+            version: 0x05,
+            choose: 0,
+        })
+    }
+    async fn request(&self, _: &Context, _: Request) -> Result<Decision<v5::Reply>, Error> {
+        Ok(Decision::Grant)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::DEBUG)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    info!("example of a simple SOCKS server listening on :1080");
+    info!("all requests will be granted");
+
+    let server = socks::Socks::new(Example::new());
+
+    server.listen("127.0.0.1:1080").await.unwrap();
+}
@@ -14,7 +14,11 @@ http --proxy=http:socks4://localhost:1080 http://example.com
 
 use std::io::Error;
 
-use ::socks::v4::{client::Request, socks::Handler, Reply};
+use ::socks::{
+    async_trait,
+    common::{Context, Decision},
+    v4::{client::Request, socks::Handler, Reply},
+};
 use socks::v4::socks;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
@@ -27,9 +31,10 @@ impl Example {
     }
 }
 
+#[async_trait]
 impl Handler for Example {
-    fn request(&self, _: Request) -> Result<Reply, Error> {
-        Ok(Reply::Granted)
+    async fn request(&self, _: &Context, _: Request) -> Result<Decision<Reply>, Error> {
+        Ok(Decision::Grant)
     }
 }
 
@@ -0,0 +1,44 @@
+/*!
+This is a simple SOCKS5 client example, doing an HTTP request through a SOCKS5 server.
+
+# Usage
+
+Start the SOCKS5 server example, then run this example.
+
+```bash
+cargo run --example socks5
+cargo run --example socks5-client
+```
+*/
+
+use socks::v5::stream::Socks5Stream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, Level};
+use tracing_subscriber::FmtSubscriber;
+
+#[tokio::main]
+async fn main() {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::DEBUG)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
+    info!("example of a simple SOCKS5 client connecting through :1080");
+
+    let mut stream = Socks5Stream::connect("127.0.0.1:1080", ("example.com", 80))
+        .await
+        .unwrap();
+
+    info!(bound_addr = %stream.bound_addr(), "connected to example.com through proxy");
+
+    stream
+        .write_all(b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    info!("{}", response);
+}
@@ -14,11 +14,15 @@ http --proxy=http:socks5://localhost:1080 http://example.com
 
 use std::io::Error;
 
-use ::socks::v5::{
-    client::{Greeting, Request},
-    server::Choice,
-    socks::Handler,
-    Reply,
+use ::socks::{
+    async_trait,
+    common::{Context, Decision},
+    v5::{
+        client::{Greeting, Request},
+        server::Choice,
+        socks::Handler,
+        Reply,
+    },
 };
 use socks::v5::socks;
 use tracing::{info, Level};
@@ -32,15 +36,16 @@ impl Example {
     }
 }
 
+#[async_trait]
 impl Handler for Example {
-    fn auth(&self, _: Greeting) -> Result<Choice, Error> {
+    async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> {
         Ok(Choice {
             version: 0x05,
             choose: 0,
         })
     }
-    fn request(&self, _: Request) -> Result<Reply, Error> {
-        Ok(Reply::RequestGranted)
+    async fn request(&self, _: &Context, _: Request) -> Result<Decision<Reply>, Error> {
+        Ok(Decision::Grant)
     }
 }
 
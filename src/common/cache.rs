@@ -0,0 +1,574 @@
+//! Cache of the domain names resolved by the servers, so bursts of requests to the same targets
+//! are served with a single lookup.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Error, ErrorKind},
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use tokio::{sync::OnceCell, time::Instant};
+use tracing::trace;
+
+use crate::async_trait;
+
+use super::Resolver;
+#[cfg(doc)]
+use super::SystemResolver;
+
+/// Number of domain names kept in the cache, when none is set.
+pub const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// Time the addresses are cached for when the resolver gives no TTL, when none is set.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Shortest time the addresses are cached for, when none is set.
+pub const DEFAULT_CACHE_MIN_TTL: Duration = Duration::from_secs(1);
+
+/// Longest time the addresses are cached for, when none is set.
+pub const DEFAULT_CACHE_MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// Time a domain name without addresses is cached for, when none is set.
+pub const DEFAULT_CACHE_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+/// Statistics of a [`CachingResolver`].
+#[derive(Debug, Default, Clone)]
+pub struct CacheStats {
+    /// Lookups answered with cached addresses.
+    pub hits: u64,
+    /// Lookups answered with a cached failure, for a domain name without addresses.
+    pub negative_hits: u64,
+    /// Lookups sent to the inner resolver, as the domain name was not cached or had expired.
+    pub misses: u64,
+    /// Lookups answered by a lookup of the same domain name already in flight.
+    pub coalesced: u64,
+    /// Domain names removed from the cache to make room for others.
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Creates a new instance with zeroed statistics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Resolver caching the lookups of another one.
+///
+/// The addresses are cached for the TTL given by the inner resolver, or the default TTL when it
+/// gives none, clamped between the minimum and maximum TTL. The [`SystemResolver`] gives no TTL,
+/// so its addresses are always cached for the default TTL, and the TTL of the records only applies
+/// with resolvers that override [`Resolver::lookup`]. Domain names without addresses, which
+/// fail with [`crate::Error::Resolution`], are cached for the negative TTL, while other failures,
+/// such as timeouts, are not cached. Once the capacity is reached, the least recently used domain
+/// name is evicted.
+///
+/// Lookups of a domain name that is not cached wait for the one already in flight, if any,
+/// instead of sending their own.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::{sync::Arc, time::Duration};
+///
+/// use socks::{
+///     common::{CachingResolver, SystemResolver},
+///     v5::socks::Socks,
+/// };
+/// # use std::io::Error;
+/// # use socks::{async_trait, common::{Context, Decision}, v5::{client::{Greeting, Request}, server::Choice, socks::Handler, Reply}};
+/// # struct Example;
+/// # #[async_trait]
+/// # impl Handler for Example {
+/// #     async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> { Ok(Choice::default()) }
+/// #     async fn request(&self, _: &Context, _: Request) -> Result<Decision<Reply>, Error> { Ok(Decision::Grant) }
+/// # }
+///
+/// let cache = Arc::new(
+///     CachingResolver::new(SystemResolver)
+///         .with_capacity(512)
+///         .with_max_ttl(Duration::from_secs(300)),
+/// );
+///
+/// let server = Socks::new(Example).with_resolver(Arc::clone(&cache));
+///
+/// // The cache is kept to read its statistics while the server runs.
+/// let stats = cache.stats();
+/// println!("{} hits, {} misses", stats.hits, stats.misses);
+/// ```
+pub struct CachingResolver<R> {
+    inner: R,
+    capacity: usize,
+    default_ttl: Duration,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    state: Mutex<State>,
+}
+
+/// Cached domain names, the order they were last used in, and the lookups in flight.
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    in_flight: HashMap<String, Arc<OnceCell<Outcome>>>,
+    stats: CacheStats,
+}
+
+struct Entry {
+    /// Addresses of the domain name, or `None` when it has none.
+    addrs: Option<Vec<IpAddr>>,
+    expires: Instant,
+    used: u64,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        CachingResolver {
+            inner,
+            capacity: DEFAULT_CACHE_CAPACITY,
+            default_ttl: DEFAULT_CACHE_TTL,
+            min_ttl: DEFAULT_CACHE_MIN_TTL,
+            max_ttl: DEFAULT_CACHE_MAX_TTL,
+            negative_ttl: DEFAULT_CACHE_NEGATIVE_TTL,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    pub fn with_min_ttl(mut self, ttl: Duration) -> Self {
+        self.min_ttl = ttl;
+        self
+    }
+
+    pub fn with_max_ttl(mut self, ttl: Duration) -> Self {
+        self.max_ttl = ttl;
+        self
+    }
+
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Returns the statistics of the cache since it was created.
+    pub fn stats(&self) -> CacheStats {
+        self.state().stats.clone()
+    }
+
+    /// Removes every cached domain name.
+    pub fn clear(&self) {
+        let mut state = self.state();
+
+        state.entries.clear();
+        state.recency.clear();
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+
+    /// Returns the cached addresses of the domain name, if they have not expired.
+    fn get(&self, domain: &str) -> Option<Option<Vec<IpAddr>>> {
+        let mut state = self.state();
+        let state = &mut *state;
+
+        let entry = state.entries.get_mut(domain)?;
+
+        if entry.expires <= Instant::now() {
+            state.recency.remove(&entry.used);
+            state.entries.remove(domain);
+
+            return None;
+        }
+
+        state.clock += 1;
+        state.recency.remove(&entry.used);
+        state.recency.insert(state.clock, domain.to_string());
+        entry.used = state.clock;
+
+        match entry.addrs {
+            Some(_) => state.stats.hits += 1,
+            None => state.stats.negative_hits += 1,
+        }
+
+        Some(entry.addrs.clone())
+    }
+
+    /// Caches the addresses of the domain name for the TTL, evicting the least recently used
+    /// domain name when the cache is full.
+    fn insert(&self, domain: &str, addrs: Option<Vec<IpAddr>>, ttl: Duration) {
+        if self.capacity == 0 || ttl.is_zero() {
+            return;
+        }
+
+        let mut state = self.state();
+
+        if let Some(entry) = state.entries.remove(domain) {
+            state.recency.remove(&entry.used);
+        }
+
+        while state.entries.len() >= self.capacity {
+            let Some((_, evicted)) = state.recency.pop_first() else {
+                break;
+            };
+
+            trace!(domain = %evicted, "evicting least recently used domain from cache");
+
+            state.entries.remove(&evicted);
+            state.stats.evictions += 1;
+        }
+
+        state.clock += 1;
+        let used = state.clock;
+
+        state.recency.insert(used, domain.to_string());
+        state.entries.insert(
+            domain.to_string(),
+            Entry {
+                addrs,
+                expires: Instant::now() + ttl,
+                used,
+            },
+        );
+    }
+
+    /// Looks the domain name up with the inner resolver and caches the outcome.
+    async fn lookup(&self, domain: &str, key: &str) -> Outcome {
+        self.state().stats.misses += 1;
+
+        let outcome = match self.inner.lookup(domain).await {
+            Ok(lookup) => {
+                let ttl = lookup
+                    .ttl
+                    .unwrap_or(self.default_ttl)
+                    .max(self.min_ttl)
+                    .min(self.max_ttl);
+
+                trace!(domain = %domain, ttl = ?ttl, "caching resolved domain");
+
+                self.insert(key, Some(lookup.addrs.clone()), ttl);
+
+                Outcome::Found(lookup.addrs)
+            }
+            Err(e) if is_resolution(&e) => {
+                trace!(domain = %domain, ttl = ?self.negative_ttl, "caching unresolvable domain");
+
+                self.insert(key, None, self.negative_ttl);
+
+                Outcome::NotFound
+            }
+            Err(e) => Outcome::Failed(e.kind(), e.to_string()),
+        };
+
+        outcome
+    }
+}
+
+/// Lookup of a domain name in flight, removed from the lookups in flight once it completes or is
+/// cancelled, so the cell it was to fill is not left behind.
+struct InFlight<'a> {
+    state: &'a Mutex<State>,
+    key: &'a str,
+    cell: &'a Arc<OnceCell<Outcome>>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut state = lock(self.state);
+
+        // NOTE: A lookup of the domain name started after this one was removed is left in flight.
+        if state
+            .in_flight
+            .get(self.key)
+            .is_some_and(|cell| Arc::ptr_eq(cell, self.cell))
+        {
+            state.in_flight.remove(self.key);
+        }
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    // NOTE: The state is consistent between statements, so it is used even when another thread
+    // panicked while holding the lock.
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Returns true when the lookup failed because the domain name has no address, as the resolver
+/// reported with [`crate::Error::Resolution`], rather than for a reason that may pass.
+fn is_resolution(error: &Error) -> bool {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<crate::Error>())
+        .is_some_and(|e| matches!(e, crate::Error::Resolution(_)))
+}
+
+/// Outcome of a lookup, shared with the lookups of the same domain name made while it was in
+/// flight.
+enum Outcome {
+    Found(Vec<IpAddr>),
+    NotFound,
+    Failed(ErrorKind, String),
+}
+
+#[async_trait]
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, Error> {
+        // NOTE: Domain names are case-insensitive, so they are cached in lowercase.
+        let key = domain.to_ascii_lowercase();
+
+        if let Some(addrs) = self.get(&key) {
+            trace!(domain = %domain, negative = addrs.is_none(), "domain resolved from cache");
+
+            return addrs.ok_or_else(|| crate::Error::Resolution(domain.to_string()).into());
+        }
+
+        // NOTE: The lookups of a domain name made while one is in flight wait for its outcome
+        // instead of sending their own, so a burst of requests to a name not cached yet is
+        // served with a single lookup.
+        let in_flight = Arc::clone(self.state().in_flight.entry(key.clone()).or_default());
+
+        let mut looked_up = false;
+        let flag = &mut looked_up;
+
+        let cell = &in_flight;
+
+        let outcome = in_flight
+            .get_or_init(|| async move {
+                *flag = true;
+
+                // NOTE: The outcome is cached before the lookup stops being in flight, so the
+                // lookups made from then on find it in the cache, or send their own when it is not
+                // cached.
+                let _in_flight = InFlight {
+                    state: &self.state,
+                    key: &key,
+                    cell,
+                };
+
+                self.lookup(domain, &key).await
+            })
+            .await;
+
+        if !looked_up {
+            trace!(domain = %domain, "domain resolved by lookup in flight");
+
+            self.state().stats.coalesced += 1;
+        }
+
+        match outcome {
+            Outcome::Found(addrs) => Ok(addrs.clone()),
+            Outcome::NotFound => Err(crate::Error::Resolution(domain.to_string()).into()),
+            Outcome::Failed(kind, message) => Err(Error::new(*kind, message.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Error, ErrorKind},
+        net::IpAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::time;
+
+    use super::{CachingResolver, DEFAULT_CACHE_TTL};
+    use crate::{
+        async_trait,
+        common::{Lookup, Resolver},
+    };
+
+    /// Resolver counting its lookups, which take a second and give the TTL set on it.
+    ///
+    /// Domain names starting with "missing" have no address, and the ones starting with "failing"
+    /// fail with a timeout.
+    #[derive(Default)]
+    struct Counting {
+        lookups: AtomicUsize,
+        ttl: Option<Duration>,
+    }
+
+    impl Counting {
+        fn lookups(&self) -> usize {
+            self.lookups.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl Resolver for Counting {
+        async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, Error> {
+            self.lookup(domain).await.map(|lookup| lookup.addrs)
+        }
+
+        async fn lookup(&self, domain: &str) -> Result<Lookup, Error> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            time::sleep(Duration::from_secs(1)).await;
+
+            if domain.starts_with("missing") {
+                return Err(crate::Error::Resolution(domain.to_string()).into());
+            }
+
+            if domain.starts_with("failing") {
+                return Err(Error::new(ErrorKind::TimedOut, "lookup timed out"));
+            }
+
+            Ok(Lookup {
+                addrs: vec![IpAddr::from([192, 0, 2, 1])],
+                ttl: self.ttl,
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caches_addresses_for_default_ttl() {
+        let inner = Arc::new(Counting::default());
+        let cache = CachingResolver::new(Arc::clone(&inner));
+
+        let addrs = cache.resolve("example.com").await.unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([192, 0, 2, 1])]);
+
+        cache.resolve("EXAMPLE.com").await.unwrap();
+        assert_eq!(inner.lookups(), 1);
+
+        time::advance(DEFAULT_CACHE_TTL).await;
+
+        cache.resolve("example.com").await.unwrap();
+        assert_eq!(inner.lookups(), 2);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clamps_ttl() {
+        let inner = Arc::new(Counting {
+            ttl: Some(Duration::from_secs(86400)),
+            ..Counting::default()
+        });
+        let cache = CachingResolver::new(Arc::clone(&inner)).with_max_ttl(Duration::from_secs(10));
+
+        cache.resolve("example.com").await.unwrap();
+        time::advance(Duration::from_secs(9)).await;
+        cache.resolve("example.com").await.unwrap();
+        assert_eq!(inner.lookups(), 1);
+
+        time::advance(Duration::from_secs(1)).await;
+        cache.resolve("example.com").await.unwrap();
+        assert_eq!(inner.lookups(), 2);
+
+        let inner = Arc::new(Counting {
+            ttl: Some(Duration::ZERO),
+            ..Counting::default()
+        });
+        let cache = CachingResolver::new(Arc::clone(&inner)).with_min_ttl(Duration::from_secs(5));
+
+        cache.resolve("example.com").await.unwrap();
+        time::advance(Duration::from_secs(4)).await;
+        cache.resolve("example.com").await.unwrap();
+        assert_eq!(inner.lookups(), 1);
+
+        time::advance(Duration::from_secs(1)).await;
+        cache.resolve("example.com").await.unwrap();
+        assert_eq!(inner.lookups(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_least_recently_used() {
+        let inner = Arc::new(Counting::default());
+        let cache = CachingResolver::new(Arc::clone(&inner)).with_capacity(2);
+
+        cache.resolve("a.example").await.unwrap();
+        cache.resolve("b.example").await.unwrap();
+
+        // NOTE: Using "a.example" makes "b.example" the least recently used.
+        cache.resolve("a.example").await.unwrap();
+        cache.resolve("c.example").await.unwrap();
+        assert_eq!(inner.lookups(), 3);
+        assert_eq!(cache.stats().evictions, 1);
+
+        cache.resolve("a.example").await.unwrap();
+        cache.resolve("c.example").await.unwrap();
+        assert_eq!(inner.lookups(), 3);
+
+        cache.resolve("b.example").await.unwrap();
+        assert_eq!(inner.lookups(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caches_missing_domains_only() {
+        let inner = Arc::new(Counting::default());
+        let cache =
+            CachingResolver::new(Arc::clone(&inner)).with_negative_ttl(Duration::from_secs(30));
+
+        for _ in 0..2 {
+            let error = cache.resolve("missing.example").await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::NotFound);
+        }
+        assert_eq!(inner.lookups(), 1);
+        assert_eq!(cache.stats().negative_hits, 1);
+
+        time::advance(Duration::from_secs(30)).await;
+        cache.resolve("missing.example").await.unwrap_err();
+        assert_eq!(inner.lookups(), 2);
+
+        for _ in 0..2 {
+            let error = cache.resolve("failing.example").await.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::TimedOut);
+        }
+        assert_eq!(inner.lookups(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn merges_concurrent_lookups() {
+        let inner = Arc::new(Counting::default());
+        let cache = Arc::new(CachingResolver::new(Arc::clone(&inner)));
+
+        let lookups: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                tokio::spawn(async move { cache.resolve("example.com").await })
+            })
+            .collect();
+
+        for lookup in lookups {
+            lookup.await.unwrap().unwrap();
+        }
+
+        assert_eq!(inner.lookups(), 1);
+
+        let stats = cache.stats();
+        assert_eq!((stats.misses, stats.coalesced), (1, 3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_lookup_is_not_left_in_flight() {
+        let inner = Arc::new(Counting::default());
+        let cache = CachingResolver::new(Arc::clone(&inner));
+
+        let cancelled = time::timeout(Duration::from_millis(500), cache.resolve("example.com"));
+        assert!(cancelled.await.is_err());
+        assert!(cache.state().in_flight.is_empty());
+
+        cache.resolve("example.com").await.unwrap();
+        assert_eq!(inner.lookups(), 2);
+        assert!(cache.state().in_flight.is_empty());
+    }
+}
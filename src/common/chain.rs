@@ -0,0 +1,303 @@
+//! Chaining of outbound connections through upstream proxy servers.
+
+use std::{
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    async_trait,
+    v4::stream::Socks4Stream,
+    v5::stream::{Auth, Socks5Stream},
+};
+
+use super::{BoxStream, Context, Dialer, Target};
+
+/// Time given to each hop to connect and complete its handshake, when none is set.
+pub const DEFAULT_HOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum size of the response head of an HTTP proxy.
+const MAX_HTTP_RESPONSE_SIZE: usize = 8192;
+
+/// Protocol spoken with an upstream proxy.
+#[derive(Debug, Clone)]
+pub enum Protocol {
+    /// SOCKS5, with optional username/password authentication.
+    Socks5(Auth),
+    /// SOCKS4, or SOCKS4a when the next address is a domain name, identifying with the user ID.
+    Socks4 { id: String },
+    /// HTTP CONNECT, with optional basic authentication.
+    Http {
+        credentials: Option<(String, String)>,
+    },
+}
+
+/// Upstream proxy a connection is chained through.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    /// Address of the proxy.
+    pub proxy: Target,
+    /// Protocol spoken with the proxy.
+    pub protocol: Protocol,
+    /// Time given to the hop to connect and complete its handshake.
+    pub timeout: Duration,
+}
+
+impl Hop {
+    pub fn new(proxy: impl Into<Target>, protocol: Protocol) -> Self {
+        Hop {
+            proxy: proxy.into(),
+            protocol,
+            timeout: DEFAULT_HOP_TIMEOUT,
+        }
+    }
+
+    /// Creates a hop through a SOCKS5 proxy, without authentication.
+    pub fn socks5(proxy: impl Into<Target>) -> Self {
+        Hop::new(proxy, Protocol::Socks5(Auth::None))
+    }
+
+    /// Creates a hop through a SOCKS5 proxy, authenticating with username and password.
+    pub fn socks5_with_password(proxy: impl Into<Target>, username: &str, password: &str) -> Self {
+        Hop::new(
+            proxy,
+            Protocol::Socks5(Auth::UsernamePassword {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+        )
+    }
+
+    /// Creates a hop through a SOCKS4 proxy, identifying with the user ID.
+    pub fn socks4(proxy: impl Into<Target>, id: &str) -> Self {
+        Hop::new(proxy, Protocol::Socks4 { id: id.to_string() })
+    }
+
+    /// Creates a hop through an HTTP proxy, without authentication.
+    pub fn http(proxy: impl Into<Target>) -> Self {
+        Hop::new(proxy, Protocol::Http { credentials: None })
+    }
+
+    /// Creates a hop through an HTTP proxy, authenticating with basic authentication.
+    pub fn http_with_password(proxy: impl Into<Target>, username: &str, password: &str) -> Self {
+        Hop::new(
+            proxy,
+            Protocol::Http {
+                credentials: Some((username.to_string(), password.to_string())),
+            },
+        )
+    }
+
+    /// Sets the time given to the hop to connect and complete its handshake.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Asks the proxy, through the stream connected to it, to connect to the next address.
+    async fn handshake(&self, stream: BoxStream, next: &Target) -> Result<BoxStream, Error> {
+        match &self.protocol {
+            Protocol::Socks5(auth) => Ok(Box::new(
+                Socks5Stream::connect_with(stream, next.clone(), auth.clone()).await?,
+            )),
+            Protocol::Socks4 { id } => Ok(Box::new(
+                Socks4Stream::connect_with(stream, next.clone(), id).await?,
+            )),
+            Protocol::Http { credentials } => {
+                http_connect(stream, next, credentials.as_ref()).await
+            }
+        }
+    }
+}
+
+/// Dialer connecting to the target through a chain of upstream proxies.
+///
+/// The first hop is connected to directly, its domain name being resolved by the resolver of the
+/// server, and each hop is asked to connect to the next one, the last hop connecting to the
+/// target.
+///
+/// Each hop is given its own timeout, within the connect timeout of the server
+/// [`Config`](super::Config), which bounds the whole chain: when the timeouts of the hops add up to
+/// more than it, the chain can fail with the connect timeout before a hop reaches its own.
+///
+/// Failures are returned as errors whose kind tells what went wrong, so the server can reply
+/// accordingly: a hop that takes longer than its timeout fails with [`ErrorKind::TimedOut`], and
+/// a hop that does not grant the request fails with a [`ReplyError`](crate::ReplyError) carrying
+/// its reply.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use socks::{
+///     common::{ChainDialer, Hop},
+///     v5::socks::Socks,
+/// };
+/// # use std::io::Error;
+/// # use socks::{async_trait, common::{Context, Decision}, v5::{client::{Greeting, Request}, server::Choice, socks::Handler, Reply}};
+/// # struct Example;
+/// # #[async_trait]
+/// # impl Handler for Example {
+/// #     async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> { Ok(Choice::default()) }
+/// #     async fn request(&self, _: &Context, _: Request) -> Result<Decision<Reply>, Error> { Ok(Decision::Grant) }
+/// # }
+///
+/// let dialer = ChainDialer::new(vec![
+///     Hop::http_with_password(("proxy.corp.example", 3128), "user", "secret"),
+///     Hop::socks5(("10.0.0.1", 1080)),
+/// ]);
+///
+/// let server = Socks::new(Example).with_dialer(dialer);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChainDialer {
+    hops: Vec<Hop>,
+}
+
+impl ChainDialer {
+    pub fn new(hops: Vec<Hop>) -> Self {
+        ChainDialer { hops }
+    }
+}
+
+#[async_trait]
+impl Dialer for ChainDialer {
+    async fn dial(&self, context: &Context, target: &Target) -> Result<BoxStream, Error> {
+        let mut stream = None;
+
+        for (position, hop) in self.hops.iter().enumerate() {
+            let next = match self.hops.get(position + 1) {
+                Some(next) => &next.proxy,
+                None => target,
+            };
+
+            trace!(hop = position, proxy = %hop.proxy, next = %next, "chaining through upstream proxy");
+
+            // NOTE: The timeout of the first hop covers the connection to it along with its
+            // handshake.
+            let step = async {
+                let stream = match stream.take() {
+                    Some(stream) => stream,
+                    None => connect(context, &hop.proxy).await?,
+                };
+
+                hop.handshake(stream, next).await
+            };
+
+            stream = Some(
+                time::timeout(hop.timeout, step)
+                    .await
+                    .map_err(|_| hop_timeout(hop))??,
+            );
+        }
+
+        debug!(target = %target, hops = self.hops.len(), "connected to target through upstream proxies");
+
+        stream.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "no upstream proxy to chain through",
+            )
+        })
+    }
+}
+
+/// Connects to the first hop, resolving its domain name with the resolver of the server and
+/// trying each address found in turn.
+async fn connect(context: &Context, proxy: &Target) -> Result<BoxStream, Error> {
+    let addrs = match proxy {
+        Target::Addr(addr) => vec![*addr],
+        Target::Domain(domain, port) => context
+            .resolve(domain)
+            .await?
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, *port))
+            .collect(),
+    };
+
+    let mut last_error = None;
+
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(Box::new(stream)),
+            Err(e) => {
+                trace!(addr = %addr, error = %e, "failed to connect to upstream proxy");
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| crate::Error::Resolution(proxy.to_string()).into()))
+}
+
+/// Creates the error for a hop that did not complete in time.
+fn hop_timeout(hop: &Hop) -> Error {
+    Error::new(
+        ErrorKind::TimedOut,
+        format!("upstream proxy {} timed out", hop.proxy),
+    )
+}
+
+/// Asks an HTTP proxy to connect to the target with the CONNECT method.
+async fn http_connect(
+    mut stream: BoxStream,
+    target: &Target,
+    credentials: Option<&(String, String)>,
+) -> Result<BoxStream, Error> {
+    let authority = match target {
+        Target::Addr(addr) => addr.to_string(),
+        Target::Domain(domain, port) => format!("{}:{}", domain, port),
+    };
+
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+
+    if let Some((username, password)) = credentials {
+        let token = STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // NOTE: The response head is read a byte at a time, so no byte sent by the target after it is
+    // consumed.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_RESPONSE_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "HTTP proxy response too large",
+            ));
+        }
+
+        head.push(stream.read_u8().await?);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid HTTP proxy response"))?;
+
+    let kind = match status {
+        200..=299 => return Ok(stream),
+        403 | 407 => ErrorKind::PermissionDenied,
+        504 => ErrorKind::TimedOut,
+        _ => ErrorKind::Other,
+    };
+
+    Err(Error::new(
+        kind,
+        format!("HTTP proxy replied with status {}", status),
+    ))
+}
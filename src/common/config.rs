@@ -0,0 +1,188 @@
+//! Configuration of the timeouts, limits and socket options of the servers.
+
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    time::Duration,
+};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{net::TcpStream, time};
+
+/// Time given to a client to complete its handshake, when none is set.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time given to the dialer to connect to the target, when none is set.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time given to the peer of a BIND request to connect, when none is set.
+pub const DEFAULT_BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time given to the active connections to finish on shutdown, when none is set.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Size of the buffers used to relay data, when none is set.
+pub const DEFAULT_BUFFER_SIZE: usize = 65535;
+
+/// Configuration shared by the SOCKS4, SOCKS5 and unified servers.
+///
+/// It is built by chaining the `with_*` methods on the default configuration, and a limit is
+/// disabled by setting its field to `None`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+///
+/// use socks::{common::Config, v5::socks::Socks};
+/// # use std::io::Error;
+/// # use socks::{async_trait, common::{Context, Decision}, v5::{client::{Greeting, Request}, server::Choice, socks::Handler, Reply}};
+/// # struct Example;
+/// # #[async_trait]
+/// # impl Handler for Example {
+/// #     async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> { Ok(Choice::default()) }
+/// #     async fn request(&self, _: &Context, _: Request) -> Result<Decision<Reply>, Error> { Ok(Decision::Grant) }
+/// # }
+///
+/// let config = Config::default()
+///     .with_handshake_timeout(Duration::from_secs(5))
+///     .with_idle_timeout(Duration::from_secs(300))
+///     .with_max_connections(1024)
+///     .with_nodelay(true);
+///
+/// let server = Socks::new(Example).with_config(config);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Time given to a client to send every packet of the handshake, from the connection to the
+    /// request.
+    pub handshake_timeout: Option<Duration>,
+    /// Time given to the dialer to connect to the target of a CONNECT request.
+    pub connect_timeout: Option<Duration>,
+    /// Time given to the peer of a BIND request to connect to the port opened for it.
+    pub bind_timeout: Option<Duration>,
+    /// Time a relay is kept open while no data flows in either direction.
+    pub idle_timeout: Option<Duration>,
+    /// Number of client connections served at once; no connection is accepted beyond it until
+    /// one is closed.
+    pub max_connections: Option<usize>,
+    /// Size of the buffers used to relay data, for each direction of each connection.
+    pub buffer_size: usize,
+    /// Whether Nagle's algorithm is disabled on the client connections accepted by the server,
+    /// and on the connections the default dialer opens to the targets.
+    pub nodelay: bool,
+    /// Idle time before TCP keepalive probes are sent on the client connections accepted by the
+    /// server, and on the connections the default dialer opens to the targets.
+    pub keepalive: Option<Duration>,
+    /// Time given to the active connections to finish on shutdown, after which they are closed;
+    /// without it, the shutdown waits for all of them.
+    pub shutdown_timeout: Option<Duration>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Config::default()
+    }
+
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_bind_timeout(mut self, timeout: Duration) -> Self {
+        self.bind_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    pub fn with_buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub fn with_keepalive(mut self, time: Duration) -> Self {
+        self.keepalive = Some(time);
+        self
+    }
+
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Applies the socket options to an accepted client connection.
+    pub(crate) fn apply(&self, stream: &TcpStream) -> Result<(), Error> {
+        set_socket_options(stream, self.nodelay, self.keepalive)
+    }
+}
+
+/// Disables Nagle's algorithm, when asked to, and enables TCP keepalive with the idle time, if
+/// any, on a connection.
+pub(crate) fn set_socket_options(
+    stream: &TcpStream,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+) -> Result<(), Error> {
+    if nodelay {
+        stream.set_nodelay(true)?;
+    }
+
+    if let Some(time) = keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+
+    Ok(())
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            bind_timeout: Some(DEFAULT_BIND_TIMEOUT),
+            idle_timeout: None,
+            max_connections: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            nodelay: false,
+            keepalive: None,
+            shutdown_timeout: Some(DEFAULT_SHUTDOWN_TIMEOUT),
+        }
+    }
+}
+
+/// Runs the future for up to the timeout, failing with [`ErrorKind::TimedOut`] when it takes
+/// longer.
+pub(crate) async fn timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, Error>>,
+    operation: &str,
+) -> Result<T, Error> {
+    match timeout {
+        Some(duration) => time::timeout(duration, future).await.unwrap_or_else(|_| {
+            Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("{} timed out", operation),
+            ))
+        }),
+        None => future.await,
+    }
+}
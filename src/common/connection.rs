@@ -1,69 +1,304 @@
-use std::io::Error;
+use std::{
+    future,
+    io::{Error, ErrorKind},
+    mem,
+    time::Duration,
+};
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    time::{self, Instant},
+};
+use tracing::trace;
+
+use crate::v5::{
+    client::{Credentials, Greeting},
+    server::{Choice, Status},
 };
 
-use crate::v5::{client::Greeting, server::Choice};
+use super::{take_frame, BoxStream, Frame, Stream, MAX_FRAME_SIZE};
 
+/// Client connection on the handshake.
+///
+/// The packets are read into a buffer, as many reads as it takes for each one to arrive, and the
+/// bytes the client sent after them are kept, so clients that send the next packet without
+/// waiting for a reply are served.
+///
+/// Any stream can be served, as long as it is reliable and ordered like a TCP connection.
 pub struct Connection {
-    stream: TcpStream,
+    stream: BoxStream,
+    buffer: Vec<u8>,
+    deadline: Option<Instant>,
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
-        Connection { stream }
+    pub fn new(stream: impl Stream + 'static) -> Self {
+        Connection {
+            stream: Box::new(stream),
+            buffer: Vec::new(),
+            deadline: None,
+        }
     }
 
-    /// Reads a greeting from the stream and converts it into a Greeting struct.
-    /// Greating is expected to be in the format defined by the SOCKS5 protocol.
-    pub async fn read_greeting(&mut self, buffer: &mut [u8]) -> Result<Greeting, Error> {
-        let size = self.stream.read(buffer).await?;
+    /// Sets the time, from now, given to the client to send the packets read from the stream,
+    /// after which reading fails with [`ErrorKind::TimedOut`].
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self
+    }
+
+    /// Reads a packet from the stream, keeping the bytes sent after it.
+    pub async fn read_frame<F>(&mut self) -> Result<F, crate::Error>
+    where
+        F: Frame + for<'a> TryFrom<&'a [u8], Error = crate::Error>,
+    {
+        loop {
+            if let Some(frame) = take_frame(&mut self.buffer)? {
+                return Ok(frame);
+            }
+
+            self.fill().await?;
+        }
+    }
+
+    /// Returns the first byte sent by the client, without consuming it.
+    pub async fn peek(&mut self) -> Result<u8, crate::Error> {
+        if self.buffer.is_empty() {
+            self.fill().await?;
+        }
+
+        Ok(self.buffer[0])
+    }
+
+    /// Returns the bytes sent by the client and not consumed yet, reading from the stream first
+    /// when there are none, so they can be fed to a state machine of the handshake.
+    pub async fn read(&mut self) -> Result<Vec<u8>, crate::Error> {
+        if self.buffer.is_empty() {
+            self.fill().await?;
+        }
+
+        Ok(mem::take(&mut self.buffer))
+    }
+
+    /// Puts bytes back in front of the ones kept from the stream, such as the bytes a state
+    /// machine received after the handshake, so they are relayed first.
+    pub fn unread(&mut self, mut bytes: Vec<u8>) {
+        bytes.append(&mut self.buffer);
+        self.buffer = bytes;
+    }
+
+    /// Reads from the stream into the buffer once, failing when the stream is closed.
+    async fn fill(&mut self) -> Result<(), crate::Error> {
+        let position = self.buffer.len();
+        self.buffer.resize(MAX_FRAME_SIZE, 0);
+
+        let read = self.stream.read(&mut self.buffer[position..]);
+        let read = match self.deadline {
+            Some(deadline) => time::timeout_at(deadline, read)
+                .await
+                .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "handshake timed out"))),
+            None => read.await,
+        };
+
+        let size = match read {
+            Ok(size) => size,
+            Err(e) => {
+                self.buffer.truncate(position);
+
+                return Err(e.into());
+            }
+        };
+
+        self.buffer.truncate(position + size);
+
         if size == 0 {
-            return Err(Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "stream closed",
-            ));
+            return Err(Error::new(ErrorKind::UnexpectedEof, "stream closed").into());
+        }
+
+        trace!(
+            bytes = size,
+            buffered = self.buffer.len(),
+            "read from client"
+        );
+
+        Ok(())
+    }
+
+    /// Waits until the client closes the stream, returning the error it was closed with.
+    ///
+    /// The bytes the client sends meanwhile are kept, up to the size of a packet, and must be
+    /// relayed before anything else read from the stream.
+    pub async fn closed(&mut self) -> Error {
+        let mut buffer = [0u8; 1024];
+
+        loop {
+            // NOTE: Once the buffer is full, the stream is no longer read, so the client is held
+            // back by TCP flow control instead.
+            if self.buffer.len() >= MAX_FRAME_SIZE {
+                return future::pending().await;
+            }
+
+            let limit = buffer.len().min(MAX_FRAME_SIZE - self.buffer.len());
+
+            match self.stream.read(&mut buffer[..limit]).await {
+                Ok(0) => return Error::new(ErrorKind::UnexpectedEof, "stream closed"),
+                Ok(size) => self.buffer.extend_from_slice(&buffer[..size]),
+                Err(e) => return e,
+            }
         }
+    }
 
-        Ok(Greeting::from(&buffer[..size]))
+    /// Reads a greeting from the stream and converts it into a Greeting struct.
+    /// Greating is expected to be in the format defined by the SOCKS5 protocol.
+    pub async fn read_greeting(&mut self) -> Result<Greeting, crate::Error> {
+        self.read_frame().await
     }
 
     /// Writes a choice to the stream.
     /// The choice is expected to be in the format defined by the SOCKS5 protocol.
     pub async fn write_choice(&mut self, choice: Choice) -> Result<(), Error> {
         let choice_buffer: [u8; 2] = choice.into();
-        self.stream.write(&choice_buffer).await?;
+        self.stream.write_all(&choice_buffer).await?;
+
+        Ok(())
+    }
+
+    /// Reads a username/password authentication request from the stream, as defined by RFC 1929.
+    pub async fn read_credentials(&mut self) -> Result<Credentials, crate::Error> {
+        self.read_frame().await
+    }
+
+    /// Writes a username/password authentication status to the stream.
+    pub async fn write_status(&mut self, status: Status) -> Result<(), Error> {
+        let status_buffer: [u8; 2] = status.into();
+        self.stream.write_all(&status_buffer).await?;
 
         Ok(())
     }
 
     /// Reads a request from the stream and converts it into the specified type R.
-    pub async fn read_request<R: From<Vec<u8>>>(
-        &mut self,
-        mut buffer: Vec<u8>,
-    ) -> Result<R, Error> {
-        let size = self.stream.read(&mut buffer).await?;
-        if size == 0 {
-            return Err(Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "stream closed",
-            ));
-        }
-        Ok(R::from(buffer[..size].to_vec()))
+    pub async fn read_request<R>(&mut self) -> Result<R, crate::Error>
+    where
+        R: Frame + for<'a> TryFrom<&'a [u8], Error = crate::Error>,
+    {
+        self.read_frame().await
     }
 
     // /// Writes a response to the stream.
     pub async fn write_response<R: Into<Vec<u8>>>(&mut self, response: R) -> Result<(), Error> {
         let response_buffer: Vec<u8> = response.into();
-        self.stream.write(&response_buffer).await?;
+        self.stream.write_all(&response_buffer).await?;
         Ok(())
     }
+
+    /// Returns the stream, and the bytes the client sent after the last packet read, which must be
+    /// relayed before anything else read from the stream.
+    pub fn into_parts(self) -> (BoxStream, Vec<u8>) {
+        (self.stream, self.buffer)
+    }
 }
 
-impl Into<TcpStream> for Connection {
-    fn into(self) -> TcpStream {
-        self.stream
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{self, AsyncWriteExt},
+        time,
+    };
+
+    use super::{Connection, MAX_FRAME_SIZE};
+    use crate::{v4, v5::client::Request, Command};
+
+    const GREETING: [u8; 4] = [0x05, 0x02, 0x00, 0x02];
+    const CREDENTIALS: [u8; 11] = [
+        0x01, 0x04, b'u', b's', b'e', b'r', 0x04, b'p', b'a', b's', b's',
+    ];
+    const REQUEST: [u8; 10] = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50];
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_packets_split_across_reads() {
+        let (client, server) = io::duplex(1024);
+        let mut connection = Connection::new(server);
+
+        // NOTE: Each piece is written once the previous one has been read, so every packet takes
+        // several reads to arrive, and no read returns bytes of two packets.
+        let writer = tokio::spawn(async move {
+            let mut client = client;
+
+            for piece in [&GREETING[..1], &GREETING[1..3], &GREETING[3..]]
+                .into_iter()
+                .chain(REQUEST.chunks(3))
+            {
+                client.write_all(piece).await.unwrap();
+                time::sleep(Duration::from_millis(1)).await;
+            }
+
+            client
+        });
+
+        let greeting = connection.read_greeting().await.unwrap();
+        assert_eq!(greeting.auth, vec![0x00, 0x02]);
+
+        let request = connection.read_request::<Request>().await.unwrap();
+        assert!(matches!(request.get_command(), Command::Connect));
+        assert_eq!(request.get_port(), 80);
+
+        let _client = writer.await.unwrap();
+        assert!(connection.into_parts().1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_packets_sent_in_a_single_write() {
+        let (mut client, server) = io::duplex(1024);
+        let mut connection = Connection::new(server);
+
+        client
+            .write_all(&[&GREETING[..], &CREDENTIALS, &REQUEST].concat())
+            .await
+            .unwrap();
+
+        connection.read_greeting().await.unwrap();
+
+        let credentials = connection.read_credentials().await.unwrap();
+        assert_eq!(credentials.username, "user");
+        assert_eq!(credentials.password, "pass");
+
+        let request = connection.read_request::<Request>().await.unwrap();
+        assert_eq!(request.get_port(), 80);
+    }
+
+    #[tokio::test]
+    async fn returns_bytes_sent_after_the_request() {
+        let (mut client, server) = io::duplex(1024);
+        let mut connection = Connection::new(server);
+
+        client
+            .write_all(&[&GREETING[..], &REQUEST, b"GET / HTTP/1.1\r\n"].concat())
+            .await
+            .unwrap();
+
+        connection.read_greeting().await.unwrap();
+        connection.read_request::<Request>().await.unwrap();
+
+        let (_, pending) = connection.into_parts();
+        assert_eq!(pending, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_packets_larger_than_the_maximum() {
+        let (mut client, server) = io::duplex(2 * MAX_FRAME_SIZE);
+        let mut connection = Connection::new(server);
+
+        // NOTE: The user ID of a SOCKS4 request ends at its null byte, which never comes.
+        let mut request = vec![0x04, 0x01, 0x00, 0x50, 127, 0, 0, 1];
+        request.resize(MAX_FRAME_SIZE + 1, b'a');
+        client.write_all(&request).await.unwrap();
+
+        let result = connection.read_request::<v4::client::Request>().await;
+        assert!(matches!(
+            result,
+            Err(crate::Error::Malformed("packet too large"))
+        ));
     }
 }
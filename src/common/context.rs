@@ -0,0 +1,176 @@
+//! Connection context, given to handlers alongside each packet.
+
+use std::{
+    fmt,
+    io::Error,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+#[cfg(unix)]
+use tokio::net::unix::UCred;
+
+use crate::Version;
+
+use super::{Resolver, SystemResolver};
+
+/// Identifier given to the next accepted connection.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identity of the client, as negotiated with the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identity {
+    /// User ID sent on a SOCKS4 request.
+    UserId(String),
+    /// Username verified with the username/password authentication method of SOCKS5.
+    Username(String),
+    /// Certificate the client authenticated with over TLS, verified by the server.
+    Certificate(Certificate),
+}
+
+/// Names of the certificate a client authenticated with over TLS.
+///
+/// The DER-encoded chain it was verified with is kept in [`Context::peer_certificates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    /// Distinguished name of the subject, as in `CN=client, O=Example`.
+    pub subject: String,
+    /// Common name of the subject, when it has one.
+    pub common_name: Option<String>,
+    /// DNS names, email addresses, URIs and IP addresses listed as subject alternative names.
+    pub alt_names: Vec<String>,
+}
+
+/// Client on the other end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    /// Client connected over TCP, or over any stream served with its address.
+    Addr(SocketAddr),
+    /// Client connected over a Unix domain socket, identified by the credentials of its process,
+    /// as reported by the operating system.
+    #[cfg(unix)]
+    Unix(UCred),
+}
+
+impl Peer {
+    /// Returns the address of the client, when it is connected over a network.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Peer::Addr(addr) => Some(*addr),
+            #[cfg(unix)]
+            Peer::Unix(_) => None,
+        }
+    }
+
+    /// Returns the credentials of the client process, when it is connected over a Unix domain
+    /// socket.
+    #[cfg(unix)]
+    pub fn credentials(&self) -> Option<&UCred> {
+        match self {
+            Peer::Addr(_) => None,
+            Peer::Unix(credentials) => Some(credentials),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Addr(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Peer::Unix(credentials) => {
+                write!(
+                    f,
+                    "unix:uid={},gid={}",
+                    credentials.uid(),
+                    credentials.gid()
+                )?;
+
+                match credentials.pid() {
+                    Some(pid) => write!(f, ",pid={}", pid),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer::Addr(addr)
+    }
+}
+
+#[cfg(unix)]
+impl From<UCred> for Peer {
+    fn from(credentials: UCred) -> Self {
+        Peer::Unix(credentials)
+    }
+}
+
+/// Information about the client connection a handler is called for.
+#[derive(Clone)]
+pub struct Context {
+    /// Identifier of the connection, unique for the running process.
+    pub id: u64,
+    /// Client the connection comes from.
+    pub peer: Peer,
+    /// Address of the server the client is connected to, where the sockets for BIND and UDP
+    /// ASSOCIATE requests are opened; the loopback address for Unix domain sockets.
+    pub local_addr: SocketAddr,
+    /// Protocol version spoken by the client.
+    pub version: Version,
+    /// Identity of the client, once negotiated: the certificate it authenticated with over TLS,
+    /// replaced by the username or user ID it then sends, if any.
+    pub identity: Option<Identity>,
+    /// DER-encoded certificate chain the client authenticated with over TLS, end-entity first,
+    /// once verified by the server.
+    pub peer_certificates: Option<Vec<Vec<u8>>>,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl Context {
+    pub fn new(peer: Peer, local_addr: SocketAddr, version: Version) -> Self {
+        Context {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            local_addr,
+            version,
+            identity: None,
+            peer_certificates: None,
+            resolver: Arc::new(SystemResolver),
+        }
+    }
+
+    /// Sets the resolver of the server, which is the [`SystemResolver`] by default.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Returns the resolver of the server the client is connected to.
+    pub fn resolver(&self) -> &dyn Resolver {
+        &*self.resolver
+    }
+
+    /// Resolves the domain name with the resolver of the server, as its targets are.
+    pub async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, Error> {
+        self.resolver.resolve(domain).await
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("id", &self.id)
+            .field("peer", &self.peer)
+            .field("local_addr", &self.local_addr)
+            .field("version", &self.version)
+            .field("identity", &self.identity)
+            .field("peer_certificates", &self.peer_certificates)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,28 @@
+//! Decisions of handlers on the requests of clients.
+
+use super::Target;
+
+/// Decision of a handler on a request, made before the server acts on it.
+///
+/// The type of the reply sent on rejection, `R`, is the reply of the SOCKS version of the request.
+///
+/// # Example
+///
+/// ```rust
+/// use socks::{common::{Decision, Target}, v5::Reply};
+///
+/// let sinkhole: Decision<Reply> = Decision::Redirect(Target::from(("127.0.0.1", 8080)));
+/// let denied: Decision<Reply> = Decision::Reject(Reply::ConnectionNotAllowedByRuleset);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision<R> {
+    /// Grants the request, as sent by the client.
+    Grant,
+    /// Grants the request, connecting to this target instead of the requested one.
+    ///
+    /// Only CONNECT requests are redirected; on other commands, it is the same as
+    /// [`Decision::Grant`].
+    Redirect(Target),
+    /// Rejects the request, replying to the client with this reply.
+    Reject(R),
+}
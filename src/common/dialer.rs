@@ -0,0 +1,486 @@
+//! Outbound connections, opened by the server to the targets of CONNECT requests.
+
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    select,
+    task::JoinSet,
+    time,
+};
+use tracing::{field, trace, warn, Span};
+
+use crate::async_trait;
+
+use super::{config, Context, Resolver, SystemResolver, Target};
+
+/// Stream to a target, as returned by a [`Dialer`].
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Boxed stream to a target.
+pub type BoxStream = Box<dyn Stream>;
+
+/// Opens the outbound connections of a server.
+///
+/// It is called with the target of each granted CONNECT request, after the handler decided on
+/// it, and the returned stream is relayed with the client.
+///
+/// # Example
+///
+/// ```rust
+/// use std::{io::Error, net::SocketAddr};
+///
+/// use socks::{
+///     async_trait,
+///     common::{BoxStream, Context, Dialer, Target},
+/// };
+/// use tokio::net::TcpSocket;
+///
+/// /// Dialer connecting from a specific source address.
+/// struct SourceDialer {
+///     source: SocketAddr,
+/// }
+///
+/// #[async_trait]
+/// impl Dialer for SourceDialer {
+///     async fn dial(&self, _: &Context, target: &Target) -> Result<BoxStream, Error> {
+///         let addr = match target {
+///             Target::Addr(addr) => *addr,
+///             Target::Domain(..) => {
+///                 return Err(Error::new(std::io::ErrorKind::Unsupported, "domain target"))
+///             }
+///         };
+///
+///         let socket = TcpSocket::new_v4()?;
+///         socket.bind(self.source)?;
+///
+///         Ok(Box::new(socket.connect(addr).await?))
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Dialer: Send + Sync + 'static {
+    async fn dial(&self, context: &Context, target: &Target) -> Result<BoxStream, Error>;
+
+    /// Opens a connection to the target, along with the address it is connected to.
+    ///
+    /// The default implementation returns the stream of [`Dialer::dial`] without an address;
+    /// dialers that know it, such as the [`DirectDialer`], override it, so it is reported on the
+    /// [`RelayStats`](super::RelayStats) of the connection.
+    async fn dial_with_addr(
+        &self,
+        context: &Context,
+        target: &Target,
+    ) -> Result<(BoxStream, Option<SocketAddr>), Error> {
+        Ok((self.dial(context, target).await?, None))
+    }
+}
+
+/// Time given to a connection attempt before the next address is tried alongside it, when none
+/// is set, as recommended by RFC 8305.
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Address families a [`DirectDialer`] connects to the targets given as domain names over, and
+/// which one it tries first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpPreference {
+    /// Tries an IPv6 address first, alternating with the IPv4 addresses.
+    #[default]
+    Ipv6First,
+    /// Tries an IPv4 address first, alternating with the IPv6 addresses.
+    Ipv4First,
+    /// Connects only to the IPv4 addresses.
+    Ipv4Only,
+    /// Connects only to the IPv6 addresses.
+    Ipv6Only,
+}
+
+impl IpPreference {
+    /// Orders the addresses in the order they are to be tried, dropping the ones of an excluded
+    /// family.
+    fn sort(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.into_iter().partition(|addr| addr.is_ipv6());
+
+        let (first, second) = match self {
+            IpPreference::Ipv6First => (v6, v4),
+            IpPreference::Ipv4First => (v4, v6),
+            IpPreference::Ipv4Only => return v4,
+            IpPreference::Ipv6Only => return v6,
+        };
+
+        let mut sorted = Vec::with_capacity(first.len() + second.len());
+        let mut first = first.into_iter();
+        let mut second = second.into_iter();
+
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => break,
+                (a, b) => sorted.extend(a.into_iter().chain(b)),
+            }
+        }
+
+        sorted
+    }
+}
+
+/// Dialer connecting directly to the target, over TCP.
+///
+/// Targets given as domain names are resolved by its [`Resolver`], the [`SystemResolver`] by
+/// default, and connected to with the Happy Eyeballs algorithm of RFC 8305: the addresses are
+/// tried in the order of the [`IpPreference`], alternating between the families, and each
+/// attempt is given the attempt delay before the next one starts alongside it, or less when it
+/// fails. The first connection established is used, and the others are closed.
+///
+/// The address connected to is recorded on the `target_addr` field of the current span, and the
+/// socket options are set on the connection once established.
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use socks::common::{DirectDialer, IpPreference};
+///
+/// let dialer = DirectDialer::new()
+///     .with_preference(IpPreference::Ipv4First)
+///     .with_attempt_delay(Duration::from_millis(100))
+///     .with_nodelay(true)
+///     .with_keepalive(Duration::from_secs(60));
+/// ```
+#[derive(Clone)]
+pub struct DirectDialer {
+    resolver: Arc<dyn Resolver>,
+    preference: IpPreference,
+    attempt_delay: Duration,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+}
+
+impl DirectDialer {
+    pub fn new() -> Self {
+        DirectDialer::default()
+    }
+
+    /// Sets the resolver of the domain names of the targets.
+    pub fn with_resolver(mut self, resolver: impl Resolver) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Sets the address families connected over, and which one is tried first.
+    pub fn with_preference(mut self, preference: IpPreference) -> Self {
+        self.preference = preference;
+        self
+    }
+
+    /// Sets the time given to a connection attempt before the next address is tried.
+    pub fn with_attempt_delay(mut self, delay: Duration) -> Self {
+        self.attempt_delay = delay;
+        self
+    }
+
+    /// Sets whether Nagle's algorithm is disabled on the connections to the targets.
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Sets the idle time before TCP keepalive probes are sent on the connections to the targets.
+    pub fn with_keepalive(mut self, time: Duration) -> Self {
+        self.keepalive = Some(time);
+        self
+    }
+
+    /// Connects to the first address that accepts the connection, starting an attempt on the
+    /// next address whenever the last one fails or the attempt delay expires.
+    async fn race(&self, addrs: Vec<SocketAddr>) -> Result<(TcpStream, SocketAddr), Error> {
+        let mut pending = addrs.into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_error = None;
+
+        loop {
+            if let Some(addr) = pending.next() {
+                trace!(addr = %addr, "attempting connection to target address");
+
+                attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+            }
+
+            if attempts.is_empty() {
+                break;
+            }
+
+            let delay = time::sleep(self.attempt_delay);
+            tokio::pin!(delay);
+
+            // NOTE: The attempts still running once a connection is established are aborted when
+            // the set is dropped.
+            select! {
+                Some(result) = attempts.join_next() => match result {
+                    Ok((addr, Ok(stream))) => return Ok((stream, addr)),
+                    Ok((addr, Err(e))) => {
+                        trace!(addr = %addr, error = %e, "connection attempt to target address failed");
+
+                        last_error = Some(e);
+                    }
+                    Err(e) => last_error = Some(Error::other(e)),
+                },
+                _ = &mut delay, if !pending.as_slice().is_empty() => {
+                    trace!("attempt delay expired, trying next address");
+                },
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| Error::new(ErrorKind::NotFound, "no address to connect to")))
+    }
+}
+
+impl Default for DirectDialer {
+    fn default() -> Self {
+        DirectDialer {
+            resolver: Arc::new(SystemResolver),
+            preference: IpPreference::default(),
+            attempt_delay: DEFAULT_ATTEMPT_DELAY,
+            nodelay: false,
+            keepalive: None,
+        }
+    }
+}
+
+impl fmt::Debug for DirectDialer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirectDialer")
+            .field("preference", &self.preference)
+            .field("attempt_delay", &self.attempt_delay)
+            .field("nodelay", &self.nodelay)
+            .field("keepalive", &self.keepalive)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl Dialer for DirectDialer {
+    async fn dial(&self, context: &Context, target: &Target) -> Result<BoxStream, Error> {
+        Ok(self.dial_with_addr(context, target).await?.0)
+    }
+
+    async fn dial_with_addr(
+        &self,
+        _: &Context,
+        target: &Target,
+    ) -> Result<(BoxStream, Option<SocketAddr>), Error> {
+        trace!(target = %target, "connecting directly to target");
+
+        let (stream, addr) = match target {
+            Target::Addr(addr) => (TcpStream::connect(addr).await?, *addr),
+            Target::Domain(domain, port) => {
+                let addrs: Vec<SocketAddr> = self
+                    .resolver
+                    .resolve(domain)
+                    .await?
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .collect();
+
+                let addrs = self.preference.sort(addrs);
+
+                trace!(domain = %domain, addrs = ?addrs, "target resolved");
+
+                if addrs.is_empty() {
+                    return Err(crate::Error::Resolution(domain.clone()).into());
+                }
+
+                self.race(addrs).await?
+            }
+        };
+
+        Span::current().record("target_addr", field::display(addr));
+        trace!(addr = %addr, "connected to target");
+
+        if let Err(e) = config::set_socket_options(&stream, self.nodelay, self.keepalive) {
+            warn!(addr = %addr, error = %e, "failed to set options on target connection");
+        }
+
+        Ok((Box::new(stream), Some(addr)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Error,
+        net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use tokio::{
+        net::{TcpListener, TcpSocket},
+        time::Instant,
+    };
+
+    use super::{DirectDialer, IpPreference};
+    use crate::{
+        async_trait,
+        common::{Context, Dialer, Peer, Resolver, Target},
+        Version,
+    };
+
+    const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+    /// Resolver returning the same addresses for any domain name.
+    struct Fixed(Vec<IpAddr>);
+
+    #[async_trait]
+    impl Resolver for Fixed {
+        async fn resolve(&self, _: &str) -> Result<Vec<IpAddr>, Error> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// Listener on the IPv4 loopback address, which accepts the connections.
+    async fn accepting() -> TcpListener {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap()
+    }
+
+    /// Listener on the IPv6 loopback address and the port, whose backlog is filled by the
+    /// returned connection, so the connection attempts to it never complete.
+    fn unresponsive(port: u16) -> (TcpListener, net::TcpStream) {
+        let addr = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+
+        let socket = TcpSocket::new_v6().unwrap();
+        socket.bind(addr).unwrap();
+        let listener = socket.listen(0).unwrap();
+
+        let filler = net::TcpStream::connect_timeout(&addr, Duration::from_secs(1)).unwrap();
+
+        (listener, filler)
+    }
+
+    fn dialer(addrs: Vec<IpAddr>, preference: IpPreference) -> DirectDialer {
+        DirectDialer::new()
+            .with_resolver(Fixed(addrs))
+            .with_preference(preference)
+            .with_attempt_delay(ATTEMPT_DELAY)
+    }
+
+    async fn dial(dialer: &DirectDialer, port: u16) -> (SocketAddr, Duration) {
+        let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 1080));
+        let context = Context::new(Peer::Addr(local_addr), local_addr, Version::V5);
+        let target = Target::from(("example.com", port));
+
+        let start = Instant::now();
+        let (_, addr) = dialer.dial_with_addr(&context, &target).await.unwrap();
+
+        (addr.unwrap(), start.elapsed())
+    }
+
+    /// Returns the connection attempts to the port over IPv6 still waiting for an answer, from
+    /// the sockets in the SYN-SENT state.
+    #[cfg(target_os = "linux")]
+    fn attempts_to(port: u16) -> usize {
+        let table = std::fs::read_to_string("/proc/net/tcp6").unwrap();
+        let remote = format!(":{:04X}", port);
+
+        table
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+
+                Some((*fields.get(2)?, *fields.get(3)?))
+            })
+            .filter(|(addr, state)| addr.ends_with(&remote) && *state == "02")
+            .count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connects_to_first_family_without_delay() {
+        let listener = accepting().await;
+        let port = listener.local_addr().unwrap().port();
+        let (_unresponsive, _filler) = unresponsive(port);
+
+        let dialer = dialer(
+            vec![Ipv6Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into()],
+            IpPreference::Ipv4First,
+        );
+
+        let (addr, elapsed) = dial(&dialer, port).await;
+
+        assert_eq!(addr, SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        assert!(elapsed < ATTEMPT_DELAY, "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn alternates_families_after_attempt_delay() {
+        let listener = accepting().await;
+        let port = listener.local_addr().unwrap().port();
+        let (_unresponsive, _filler) = unresponsive(port);
+
+        // NOTE: Both IPv6 addresses come first from the resolver, so the IPv4 address is tried
+        // after a single attempt delay only when the families alternate.
+        let dialer = dialer(
+            vec![
+                Ipv6Addr::LOCALHOST.into(),
+                Ipv6Addr::LOCALHOST.into(),
+                Ipv4Addr::LOCALHOST.into(),
+            ],
+            IpPreference::Ipv6First,
+        );
+
+        let (addr, elapsed) = dial(&dialer, port).await;
+
+        assert_eq!(addr, SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        assert!(
+            elapsed >= ATTEMPT_DELAY && elapsed < ATTEMPT_DELAY * 2,
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tries_next_address_when_attempt_fails() {
+        let listener = accepting().await;
+        let port = listener.local_addr().unwrap().port();
+
+        // NOTE: Nothing listens on the IPv6 address, so its attempt is refused at once.
+        let dialer = dialer(
+            vec![Ipv6Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into()],
+            IpPreference::Ipv6First,
+        );
+
+        let (addr, elapsed) = dial(&dialer, port).await;
+
+        assert_eq!(addr, SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        assert!(elapsed < ATTEMPT_DELAY, "{:?}", elapsed);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test(start_paused = true)]
+    async fn cancels_losing_attempts() {
+        let listener = accepting().await;
+        let port = listener.local_addr().unwrap().port();
+        let (_unresponsive, _filler) = unresponsive(port);
+
+        let dialer = dialer(
+            vec![Ipv6Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into()],
+            IpPreference::Ipv6First,
+        );
+
+        dial(&dialer, port).await;
+
+        // NOTE: The aborted attempts are dropped once their tasks are polled again.
+        tokio::task::yield_now().await;
+
+        assert_eq!(attempts_to(port), 0);
+    }
+}
@@ -0,0 +1,33 @@
+//! Framing of the packets exchanged on the handshake.
+
+/// Maximum size of a packet read on the handshake.
+pub const MAX_FRAME_SIZE: usize = 4096;
+
+/// Packet whose length is told by its first bytes.
+///
+/// It lets a packet be read from a stream in as many reads as it takes to arrive, without
+/// consuming the bytes sent after it.
+pub trait Frame: Sized {
+    /// Returns the length of the packet at the start of the buffer, or `None` when more bytes are
+    /// needed to tell it.
+    fn frame_len(buffer: &[u8]) -> Result<Option<usize>, crate::Error>;
+}
+
+/// Takes a packet from the start of the buffer, leaving the bytes after it, or returns `None`
+/// when the whole packet is not in the buffer yet.
+pub fn take_frame<F>(buffer: &mut Vec<u8>) -> Result<Option<F>, crate::Error>
+where
+    F: Frame + for<'a> TryFrom<&'a [u8], Error = crate::Error>,
+{
+    match F::frame_len(buffer)? {
+        Some(length) if length > MAX_FRAME_SIZE => Err(crate::Error::Malformed("packet too large")),
+        Some(length) if buffer.len() >= length => {
+            let frame = F::try_from(&buffer[..length]);
+            buffer.drain(..length);
+
+            frame.map(Some)
+        }
+        None if buffer.len() >= MAX_FRAME_SIZE => Err(crate::Error::Malformed("packet too large")),
+        _ => Ok(None),
+    }
+}
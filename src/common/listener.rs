@@ -0,0 +1,343 @@
+//! Accept loop shared by the servers, and their graceful shutdown.
+
+use std::{future::Future, io::Error, net::SocketAddr, sync::Arc, time::Duration};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time,
+};
+use tracing::{debug, error, warn};
+
+use super::{BoxStream, Config, Peer};
+
+/// Time waited after a failed accept before accepting again.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Statistics of the connections closed on the shutdown of a server.
+#[derive(Debug, Default)]
+pub struct ShutdownStats {
+    /// Connections that finished by themselves within the shutdown timeout.
+    pub drained: u64,
+    /// Connections still active when the shutdown timeout expired, which were closed.
+    pub aborted: u64,
+}
+
+impl ShutdownStats {
+    /// Creates a new instance with zeroed statistics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Listener a server accepts its connections on.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Connection accepted on a [`Listener`].
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> Result<Accepted, Error> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+
+                Ok(Accepted::Tcp(stream, peer_addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+
+                Ok(Accepted::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Accepted {
+    /// Returns the stream, the client and the local address of the connection, with the socket
+    /// options of the configuration applied.
+    fn into_parts(self, config: &Config) -> Result<(BoxStream, Peer, SocketAddr), Error> {
+        match self {
+            Accepted::Tcp(stream, peer_addr) => {
+                if let Err(e) = config.apply(&stream) {
+                    warn!(peer_addr = %peer_addr, error = %e, "failed to set options on client connection");
+                }
+
+                let local_addr = stream.local_addr()?;
+
+                Ok((Box::new(stream), Peer::Addr(peer_addr), local_addr))
+            }
+            // NOTE: The client of a Unix domain socket runs on the same host, so the sockets for
+            // its BIND and UDP ASSOCIATE requests are opened on the loopback address.
+            #[cfg(unix)]
+            Accepted::Unix(stream) => {
+                let credentials = stream.peer_cred()?;
+
+                Ok((
+                    Box::new(stream),
+                    Peer::Unix(credentials),
+                    SocketAddr::from(([127, 0, 0, 1], 0)),
+                ))
+            }
+        }
+    }
+}
+
+/// Accepts connections on the listener, serving each one on its own task, until the shutdown
+/// future completes.
+///
+/// Once it does, the listener is closed and the active connections are given the shutdown
+/// timeout of the configuration to finish, after which the remaining ones are closed. A failure to
+/// accept a connection is logged, and the server keeps accepting after a short delay.
+pub(crate) async fn run<S, F>(
+    listener: Listener,
+    config: &Config,
+    shutdown: impl Future<Output = ()>,
+    serve: S,
+) -> Result<ShutdownStats, Error>
+where
+    S: Fn(BoxStream, Peer, SocketAddr) -> F,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let connections = config
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+
+    let mut tasks = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (accepted, permit) = select! {
+            _ = &mut shutdown => break,
+            Some(result) = tasks.join_next(), if !tasks.is_empty() => {
+                if let Err(e) = result {
+                    error!(error = %e, "connection task failed");
+                }
+
+                continue;
+            },
+            accepted = accept(&listener, connections.as_ref()) => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // NOTE: Accepting fails on conditions that pass, such as running out of file
+                    // descriptors under load, so the server waits a moment and keeps accepting
+                    // instead of closing the active connections.
+                    error!(error = %e, "failed to accept client connection");
+                    time::sleep(ACCEPT_ERROR_DELAY).await;
+
+                    continue;
+                }
+            },
+        };
+
+        let (stream, peer, local_addr) = match accepted.into_parts(config) {
+            Ok(parts) => parts,
+            Err(e) => {
+                error!(error = %e, "failed to get addresses of client connection");
+
+                continue;
+            }
+        };
+
+        debug!(peer = %peer, "new client connection accepted");
+
+        let connection = serve(stream, peer, local_addr);
+
+        tasks.spawn(async move {
+            connection.await;
+
+            drop(permit);
+        });
+    }
+
+    drop(listener);
+
+    debug!(
+        active = tasks.len(),
+        "shutting down, no longer accepting connections"
+    );
+
+    let mut stats = ShutdownStats::new();
+
+    let drain = async {
+        while let Some(result) = tasks.join_next().await {
+            if let Err(e) = result {
+                error!(error = %e, "connection task failed");
+            }
+
+            stats.drained += 1;
+        }
+    };
+
+    match config.shutdown_timeout {
+        Some(timeout) => {
+            if time::timeout(timeout, drain).await.is_err() {
+                warn!(
+                    remaining = tasks.len(),
+                    "shutdown timeout expired, closing active connections"
+                );
+            }
+        }
+        None => drain.await,
+    }
+
+    stats.aborted = tasks.len() as u64;
+    tasks.shutdown().await;
+
+    debug!(stats.drained, stats.aborted, "server shut down");
+
+    Ok(stats)
+}
+
+/// Accepts a connection once the limit of connections allows it.
+///
+/// When the limit of connections is reached, no connection is accepted until one is closed, so
+/// the new clients wait on the listen backlog.
+async fn accept(
+    listener: &Listener,
+    connections: Option<&Arc<Semaphore>>,
+) -> Result<(Accepted, Option<OwnedSemaphorePermit>), Error> {
+    let permit = match connections {
+        Some(connections) => Some(
+            Arc::clone(connections)
+                .acquire_owned()
+                .await
+                .map_err(Error::other)?,
+        ),
+        None => None,
+    };
+
+    let accepted = listener.accept().await?;
+
+    Ok((accepted, permit))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, oneshot},
+        time::{self, Instant},
+    };
+
+    use super::{run, Listener};
+    use crate::common::Config;
+
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_drains_then_aborts_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config::default().with_shutdown_timeout(SHUTDOWN_TIMEOUT);
+
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let (served, mut accepted) = mpsc::unbounded_channel();
+
+        let server = tokio::spawn(async move {
+            let shutdown = async {
+                signal.await.ok();
+            };
+
+            // NOTE: Each connection is served until the client closes it.
+            run(
+                Listener::Tcp(listener),
+                &config,
+                shutdown,
+                |mut stream, _, _| {
+                    let served = served.clone();
+
+                    async move {
+                        served.send(()).unwrap();
+                        stream.read_u8().await.ok();
+                    }
+                },
+            )
+            .await
+        });
+
+        let closing = TcpStream::connect(addr).await.unwrap();
+        let mut open = TcpStream::connect(addr).await.unwrap();
+
+        accepted.recv().await.unwrap();
+        accepted.recv().await.unwrap();
+
+        shutdown.send(()).unwrap();
+        let start = Instant::now();
+
+        // NOTE: The paused clock only advances once the server waits for its connections.
+        time::sleep(Duration::from_millis(1)).await;
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        drop(closing);
+
+        let stats = server.await.unwrap().unwrap();
+
+        assert_eq!((stats.drained, stats.aborted), (1, 1));
+        assert!(start.elapsed() >= SHUTDOWN_TIMEOUT);
+
+        // NOTE: The aborted connection is closed by the server.
+        assert_eq!(open.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_completes_once_connections_drain() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config::default().with_shutdown_timeout(SHUTDOWN_TIMEOUT);
+
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let (served, mut accepted) = mpsc::unbounded_channel();
+
+        let server = tokio::spawn(async move {
+            let shutdown = async {
+                signal.await.ok();
+            };
+
+            run(
+                Listener::Tcp(listener),
+                &config,
+                shutdown,
+                |mut stream, _, _| {
+                    let served = served.clone();
+
+                    async move {
+                        served.send(()).unwrap();
+                        stream.read_u8().await.ok();
+                    }
+                },
+            )
+            .await
+        });
+
+        let closing = TcpStream::connect(addr).await.unwrap();
+        accepted.recv().await.unwrap();
+
+        shutdown.send(()).unwrap();
+        let start = Instant::now();
+
+        time::sleep(Duration::from_millis(1)).await;
+        drop(closing);
+
+        let stats = server.await.unwrap().unwrap();
+
+        assert_eq!((stats.drained, stats.aborted), (1, 0));
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+    }
+}
@@ -4,8 +4,32 @@
 //! connection establishment, and buffer management that are shared between
 //! SOCKS4 and SOCKS5 implementations.
 
+pub mod cache;
+pub mod chain;
+pub mod config;
 pub mod connection;
+pub mod context;
+pub mod decision;
+pub mod dialer;
+pub mod frame;
+pub mod listener;
 pub mod relay;
+pub mod resolver;
+pub mod server;
+pub mod target;
+#[cfg(feature = "tls")]
+pub mod tls;
 
+pub use cache::*;
+pub use chain::*;
+pub use config::*;
 pub use connection::*;
+pub use context::*;
+pub use decision::*;
+pub use dialer::*;
+pub use frame::*;
+pub use listener::*;
 pub use relay::*;
+pub use resolver::*;
+pub use server::*;
+pub use target::*;
@@ -1,15 +1,40 @@
 //! Data relay utilities for SOCKS protocol implementations.
 
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
     select,
+    task::JoinSet,
+    time::{self, Instant},
 };
-use tracing::{error, trace, warn};
+use tracing::{debug, error, trace, warn};
+
+use crate::v5::{socks::Handler, udp::Datagram};
+
+use super::{Config, Connection, Context, Resolver, Target};
+
+/// Number of distinct destinations recorded on the [`UdpRelayStats`] of an association.
+pub const MAX_RECORDED_DESTINATIONS: usize = 64;
+
+/// Number of datagrams from the client of an association awaiting the decision of the handler
+/// at once, beyond which the new ones are dropped.
+pub const MAX_PENDING_DATAGRAMS: usize = 64;
 
 /// Statistics for data relay operations.
 #[derive(Debug, Default)]
 pub struct RelayStats {
+    /// Target the client was relayed with: the one decided by the handler for CONNECT requests,
+    /// or the one sent on BIND requests.
+    pub target: Option<Target>,
+    /// Address connected to for the target, when the dialer reports it, or the address of the
+    /// peer that connected for BIND requests.
+    pub target_addr: Option<SocketAddr>,
     pub bytes_to_client: u64,
     pub bytes_to_target: u64,
     pub packets_to_client: u64,
@@ -23,21 +48,62 @@ impl RelayStats {
     }
 }
 
+/// Statistics for datagram relay operations.
+#[derive(Debug, Default)]
+pub struct UdpRelayStats {
+    /// Distinct destinations the datagrams of the client were sent to, along with the address
+    /// each was sent to, up to [`MAX_RECORDED_DESTINATIONS`].
+    pub destinations: Vec<(Target, SocketAddr)>,
+    pub bytes_to_client: u64,
+    pub bytes_to_target: u64,
+    pub packets_to_client: u64,
+    pub packets_to_target: u64,
+    /// Datagrams discarded because they were malformed, fragmented, from an unexpected source,
+    /// denied by the handler, over [`MAX_PENDING_DATAGRAMS`] or could not be delivered.
+    pub packets_dropped: u64,
+}
+
+impl UdpRelayStats {
+    /// Creates a new instance with zeroed statistics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
 /// Performs bidirectional data relay between two streams.
 ///
-/// This function reads data from both streams and forwards it to the other stream.
-/// It continues until either stream is closed or an error occurs.
-pub async fn relay_data(mut stream_a: TcpStream, mut stream_b: TcpStream) -> RelayStats {
-    let (mut a_read, mut a_write) = stream_a.split();
-    let (mut b_read, mut b_write) = stream_b.split();
+/// This function reads data from both streams and forwards it to the other stream. Stream A is
+/// the client and stream B the target, so the data read from A is counted as sent to the target,
+/// and the data read from B as sent to the client.
+/// It continues until either stream is closed, an error occurs or, when the configuration sets an
+/// idle timeout, no data flows for that long.
+pub async fn relay_data<A, B>(stream_a: A, stream_b: B, config: &Config) -> RelayStats
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (mut a_read, mut a_write) = io::split(stream_a);
+    let (mut b_read, mut b_write) = io::split(stream_b);
 
-    let (mut buffer_a, mut buffer_b) = (vec![0u8; 65535], vec![0u8; 65535]);
+    let (mut buffer_a, mut buffer_b) =
+        (vec![0u8; config.buffer_size], vec![0u8; config.buffer_size]);
     let mut stats = RelayStats::new();
 
+    let idle = time::sleep(config.idle_timeout.unwrap_or_default());
+    tokio::pin!(idle);
+
     trace!("starting data relay between streams");
 
     loop {
+        if let Some(timeout) = config.idle_timeout {
+            idle.as_mut().reset(Instant::now() + timeout);
+        }
+
         select! {
+            _ = &mut idle, if config.idle_timeout.is_some() => {
+                debug!("no data relayed within idle timeout, closing relay");
+                break;
+            },
             Ok(size) = a_read.read(&mut buffer_a) => {
                 if size == 0 {
                     trace!("stream A closed connection");
@@ -45,8 +111,8 @@ pub async fn relay_data(mut stream_a: TcpStream, mut stream_b: TcpStream) -> Rel
                     break;
                 }
 
-                stats.bytes_to_client += size as u64;
-                stats.packets_to_client += 1;
+                stats.bytes_to_target += size as u64;
+                stats.packets_to_target += 1;
 
                 trace!(bytes = size, "relaying data from stream A to stream B");
                 if let Err(e) = b_write.write_all(&buffer_a[..size]).await {
@@ -60,8 +126,8 @@ pub async fn relay_data(mut stream_a: TcpStream, mut stream_b: TcpStream) -> Rel
                     break;
                 }
 
-                stats.bytes_to_target += size as u64;
-                stats.packets_to_target += 1;
+                stats.bytes_to_client += size as u64;
+                stats.packets_to_client += 1;
 
                 trace!(bytes = size, "relaying data from stream B to stream A");
                 if let Err(e) = a_write.write_all(&buffer_b[..size]).await {
@@ -76,5 +142,278 @@ pub async fn relay_data(mut stream_a: TcpStream, mut stream_b: TcpStream) -> Rel
         };
     }
 
-    return stats;
+    stats
+}
+
+/// Performs bidirectional data relay between a client connection and a target.
+///
+/// The bytes the client sent after its last handshake packet are written to the target first, so
+/// data sent by clients that do not wait for the reply is not lost.
+pub async fn relay_connection<T>(
+    connection: Connection,
+    mut target: T,
+    config: &Config,
+) -> RelayStats
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (client, pending) = connection.into_parts();
+
+    if !pending.is_empty() {
+        trace!(bytes = pending.len(), "relaying data sent during handshake");
+
+        if let Err(e) = target.write_all(&pending).await {
+            error!(error = ?e, "error writing to stream B");
+
+            return RelayStats::new();
+        }
+    }
+
+    let mut stats = relay_data(client, target, config).await;
+
+    if !pending.is_empty() {
+        stats.bytes_to_target += pending.len() as u64;
+        stats.packets_to_target += 1;
+    }
+
+    stats
+}
+
+/// Performs datagram relay between a client and any target, through a UDP socket.
+///
+/// Datagrams coming from the client address have their SOCKS5 UDP header removed and are sent to
+/// the destination they carry; datagrams coming from an address the client has sent datagrams to
+/// are sent to the client with the header prepended, and the ones coming from anywhere else are
+/// dropped. When the client address has port zero, the port is taken from the first datagram sent
+/// from the client IP address. Destinations given as domain names are resolved by the resolver of
+/// the context, and the datagram is sent to the first address found. Each destination is decided
+/// on [`Handler::datagram`] first, and the datagrams it denies are dropped.
+///
+/// The decisions and resolutions run on their own tasks, up to [`MAX_PENDING_DATAGRAMS`] at once,
+/// so a slow handler or resolver does not hold up the datagrams flowing in either direction.
+///
+/// The relay lasts as long as the control stream is open, as the UDP association terminates
+/// when the TCP connection that the UDP ASSOCIATE request arrived on terminates, or until no
+/// datagram is relayed within the idle timeout of the configuration.
+pub async fn relay_datagrams<C>(
+    mut control: C,
+    socket: UdpSocket,
+    mut client_addr: SocketAddr,
+    handler: Arc<dyn Handler>,
+    context: &Context,
+    config: &Config,
+) -> UdpRelayStats
+where
+    C: AsyncRead + Unpin,
+{
+    let mut control_buffer = vec![0u8; 1024];
+    // NOTE: The buffer holds any UDP datagram, whatever the configured buffer size.
+    let mut buffer = vec![0u8; 65535];
+    let mut stats = UdpRelayStats::new();
+
+    let context = Arc::new(context.clone());
+    let mut pending: JoinSet<(Target, Vec<u8>, Option<SocketAddr>)> = JoinSet::new();
+    // NOTE: Only the targets the client has sent datagrams to can send datagrams back, so the
+    // relay does not forward whatever reaches its port to the client.
+    let mut peers = HashSet::new();
+
+    let idle = time::sleep(config.idle_timeout.unwrap_or_default());
+    tokio::pin!(idle);
+
+    trace!(client_addr = %client_addr, "starting datagram relay");
+
+    loop {
+        let relayed = select! {
+            _ = &mut idle, if config.idle_timeout.is_some() => {
+                debug!("no datagram relayed within idle timeout, closing relay");
+                break;
+            },
+            result = control.read(&mut control_buffer) => {
+                match result {
+                    Ok(0) => {
+                        trace!("control stream closed connection");
+                        break;
+                    }
+                    Ok(size) => {
+                        trace!(bytes = size, "ignoring data received on control stream");
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(error = ?e, "error reading from control stream");
+                        break;
+                    }
+                }
+            },
+            Some(result) = pending.join_next(), if !pending.is_empty() => {
+                let (target, data, target_addr) = match result {
+                    Ok((target, data, Some(target_addr))) => (target, data, target_addr),
+                    Ok(_) => {
+                        stats.packets_dropped += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(error = %e, "datagram decision task failed");
+                        stats.packets_dropped += 1;
+                        continue;
+                    }
+                };
+
+                trace!(bytes = data.len(), target_addr = %target_addr, "relaying datagram from client to target");
+                match socket.send_to(&data, target_addr).await {
+                    Ok(sent) => {
+                        stats.bytes_to_target += sent as u64;
+                        stats.packets_to_target += 1;
+                        peers.insert(target_addr);
+
+                        let destination = (target, target_addr);
+                        if stats.destinations.len() < MAX_RECORDED_DESTINATIONS
+                            && !stats.destinations.contains(&destination)
+                        {
+                            stats.destinations.push(destination);
+                        }
+
+                        true
+                    }
+                    Err(e) => {
+                        warn!(error = ?e, "error sending datagram to target");
+                        stats.packets_dropped += 1;
+
+                        false
+                    }
+                }
+            },
+            result = socket.recv_from(&mut buffer) => {
+                let (size, from) = match result {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!(error = ?e, "error receiving datagram");
+                        continue;
+                    }
+                };
+
+                let from_client = from.ip() == client_addr.ip()
+                    && (client_addr.port() == 0 || from.port() == client_addr.port());
+
+                if from_client {
+                    if client_addr.port() == 0 {
+                        trace!(client_addr = %from, "client address learned from first datagram");
+                        client_addr = from;
+                    }
+
+                    let datagram = match Datagram::try_from(&buffer[..size]) {
+                        Ok(d) => d,
+                        Err(e) => {
+                            warn!(error = %e, "dropping malformed datagram from client");
+                            stats.packets_dropped += 1;
+                            continue;
+                        }
+                    };
+
+                    if datagram.is_fragment() {
+                        trace!(frag = datagram.frag, "dropping fragmented datagram from client");
+                        stats.packets_dropped += 1;
+                        continue;
+                    }
+
+                    let target = match datagram.get_target() {
+                        Some(target) => target,
+                        None => {
+                            warn!("dropping datagram with invalid destination");
+                            stats.packets_dropped += 1;
+                            continue;
+                        }
+                    };
+
+                    if pending.len() >= MAX_PENDING_DATAGRAMS {
+                        warn!(target = %target, "dropping datagram, too many datagrams awaiting a decision");
+                        stats.packets_dropped += 1;
+                        continue;
+                    }
+
+                    let handler = Arc::clone(&handler);
+                    let context = Arc::clone(&context);
+                    pending.spawn(async move {
+                        let target_addr = decide(&*handler, &context, &target).await;
+
+                        (target, datagram.data, target_addr)
+                    });
+
+                    false
+                } else {
+                    if client_addr.port() == 0 {
+                        trace!(from = %from, "dropping datagram received before client address is known");
+                        stats.packets_dropped += 1;
+                        continue;
+                    }
+
+                    if !peers.contains(&from) {
+                        trace!(from = %from, "dropping datagram from address the client has not sent to");
+                        stats.packets_dropped += 1;
+                        continue;
+                    }
+
+                    let datagram: Vec<u8> = Datagram::new(from, buffer[..size].to_vec()).into();
+
+                    trace!(bytes = size, from = %from, "relaying datagram from target to client");
+                    match socket.send_to(&datagram, client_addr).await {
+                        Ok(_) => {
+                            stats.bytes_to_client += size as u64;
+                            stats.packets_to_client += 1;
+
+                            true
+                        }
+                        Err(e) => {
+                            warn!(error = ?e, "error sending datagram to client");
+                            stats.packets_dropped += 1;
+
+                            false
+                        }
+                    }
+                }
+            },
+        };
+
+        if let (true, Some(timeout)) = (relayed, config.idle_timeout) {
+            idle.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    stats
+}
+
+/// Decides on the destination of a datagram with the handler, and resolves it into the address
+/// the datagram is sent to, or returns nothing when the datagram is dropped.
+async fn decide(handler: &dyn Handler, context: &Context, target: &Target) -> Option<SocketAddr> {
+    match handler.datagram(context, target).await {
+        Ok(true) => {}
+        Ok(false) => {
+            debug!(target = %target, "dropping datagram to destination denied by handler");
+            return None;
+        }
+        Err(e) => {
+            error!(target = %target, error = %e, "handler failed to decide on datagram");
+            return None;
+        }
+    }
+
+    match target {
+        Target::Addr(addr) => Some(*addr),
+        Target::Domain(domain, port) => match resolve(context.resolver(), domain).await {
+            Ok(ip) => Some(SocketAddr::new(ip, *port)),
+            Err(e) => {
+                warn!(domain = %domain, error = %e, "dropping datagram with unresolvable destination");
+                None
+            }
+        },
+    }
+}
+
+/// Resolves the domain name into the first address found by the resolver.
+async fn resolve(resolver: &dyn Resolver, domain: &str) -> Result<IpAddr, io::Error> {
+    resolver
+        .resolve(domain)
+        .await?
+        .first()
+        .copied()
+        .ok_or_else(|| crate::Error::Resolution(domain.to_string()).into())
 }
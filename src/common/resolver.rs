@@ -0,0 +1,121 @@
+//! Resolution of the domain names sent by the clients, as the targets of their requests and the
+//! destinations of their datagrams.
+
+use std::{
+    io::{Error, ErrorKind},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::net;
+
+use crate::async_trait;
+
+/// Resolves the domain names of the targets into their IP addresses.
+///
+/// It is used by the [`DirectDialer`](super::DirectDialer) of the servers to connect to the
+/// targets of CONNECT requests, and by the UDP relay to send the datagrams addressed to domain
+/// names.
+///
+/// # Example
+///
+/// ```rust
+/// use std::{
+///     collections::HashMap,
+///     io::{Error, ErrorKind},
+///     net::IpAddr,
+/// };
+///
+/// use socks::{async_trait, common::{Resolver, SystemResolver}};
+///
+/// /// Resolver answering from a hosts map, and from the system resolver for the other names.
+/// struct HostsResolver {
+///     hosts: HashMap<String, IpAddr>,
+/// }
+///
+/// #[async_trait]
+/// impl Resolver for HostsResolver {
+///     async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, Error> {
+///         match self.hosts.get(domain) {
+///             Some(addr) => Ok(vec![*addr]),
+///             None => SystemResolver.resolve(domain).await,
+///         }
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Resolver: Send + Sync + 'static {
+    /// Returns the IP addresses of the domain name, in the order they are to be tried, failing
+    /// with [`crate::Error::Resolution`] when it has none, which is the only failure a
+    /// [`CachingResolver`](super::CachingResolver) caches.
+    async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, Error>;
+
+    /// Returns the IP addresses of the domain name along with the time they can be cached for.
+    ///
+    /// The default implementation returns the addresses of [`Resolver::resolve`] without a TTL;
+    /// resolvers that receive one from their source, such as DNS-over-HTTPS, override it.
+    async fn lookup(&self, domain: &str) -> Result<Lookup, Error> {
+        Ok(Lookup {
+            addrs: self.resolve(domain).await?,
+            ttl: None,
+        })
+    }
+}
+
+#[async_trait]
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, Error> {
+        (**self).resolve(domain).await
+    }
+
+    async fn lookup(&self, domain: &str) -> Result<Lookup, Error> {
+        (**self).lookup(domain).await
+    }
+}
+
+/// Addresses of a domain name, as returned by [`Resolver::lookup`].
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    /// IP addresses of the domain name, in the order they are to be tried.
+    pub addrs: Vec<IpAddr>,
+    /// Time the addresses can be cached for, when the source of the resolver gives one.
+    pub ttl: Option<Duration>,
+}
+
+/// Resolver asking the system resolver, as configured on the host.
+///
+/// The lookups run on the blocking thread pool of the runtime, so a slow resolution does not
+/// stall the other connections served on the same worker.
+///
+/// The system resolver does not tell a domain name without addresses from a failure that may
+/// pass, such as a name server that does not answer, so a failed lookup is returned with
+/// [`ErrorKind::HostUnreachable`], carrying the error it reported, and is never cached by a
+/// [`CachingResolver`](super::CachingResolver). Only a lookup that succeeds without any address
+/// fails with [`crate::Error::Resolution`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, domain: &str) -> Result<Vec<IpAddr>, Error> {
+        let found = net::lookup_host((domain, 0))
+            .await
+            .map_err(|e| Error::new(ErrorKind::HostUnreachable, e))?;
+
+        let mut addrs: Vec<IpAddr> = Vec::new();
+
+        for addr in found {
+            // NOTE: The system resolver returns an address once for each socket type.
+            if !addrs.contains(&addr.ip()) {
+                addrs.push(addr.ip());
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(crate::Error::Resolution(domain.to_string()).into());
+        }
+
+        Ok(addrs)
+    }
+}
@@ -0,0 +1,387 @@
+//! Server builder shared by the SOCKS4, SOCKS5 and unified servers, which differ only in the
+//! [`Service`] they serve their clients with.
+
+use std::{
+    future::{self, Future},
+    io::Error,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tracing::debug;
+#[cfg(feature = "tls")]
+use tracing::{error, trace, warn};
+
+use super::{
+    listener::{self, Listener},
+    BoxStream, CachingResolver, Config, Connection, Context, Dialer, DirectDialer, Peer, Resolver,
+    ShutdownStats, Stream, SystemResolver,
+};
+#[cfg(feature = "tls")]
+use super::{
+    tls::{self, rustls::ServerConfig, TlsAcceptor},
+    Identity,
+};
+use crate::Version;
+
+/// Service the clients of a [`Server`] are served with, holding its handlers.
+pub trait Service: Send + Sync + 'static {
+    /// Version set on the [`Context`] of the connections before the client sends anything.
+    const VERSION: Version;
+
+    /// Serves a client connection, from its first packet to the end of its command.
+    fn serve(
+        &self,
+        dialer: Arc<dyn Dialer>,
+        config: Arc<Config>,
+        connection: Connection,
+        context: Context,
+    ) -> impl Future<Output = ()> + Send + 'static;
+}
+
+/// SOCKS server, serving the clients it accepts with its [`Service`].
+///
+/// It is built by chaining the `with_*` methods on the server created by the `new` function of
+/// [`v4::socks::Socks`](crate::v4::socks::Socks), [`v5::socks::Socks`](crate::v5::socks::Socks)
+/// or [`socks::Socks`](crate::socks::Socks).
+pub struct Server<P> {
+    service: Arc<P>,
+    dialer: Option<Arc<dyn Dialer>>,
+    resolver: Arc<dyn Resolver>,
+    config: Arc<Config>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+}
+
+impl<P: Service> Server<P> {
+    /// Creates a server for the service, with the default dialer, resolver and
+    /// configuration.
+    pub fn from_service(service: P) -> Self {
+        Server {
+            service: Arc::new(service),
+            dialer: None,
+            resolver: Arc::new(CachingResolver::new(SystemResolver)),
+            config: Arc::new(Config::default()),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Sets the dialer used to connect to the targets of CONNECT requests, which is a
+    /// [`DirectDialer`] resolving with the resolver of the server, and setting the socket options
+    /// of its configuration, by default.
+    pub fn with_dialer(mut self, dialer: impl Dialer) -> Self {
+        self.dialer = Some(Arc::new(dialer));
+        self
+    }
+
+    /// Sets the resolver of the domain names sent by the clients, which is a [`CachingResolver`]
+    /// over the [`SystemResolver`], with its default capacity and TTLs, by default.
+    ///
+    /// It is used by the default dialer, by the UDP relay and by [`Context::resolve`], while a
+    /// dialer set with [`Server::with_dialer`] resolves the targets on its own.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::{io::Error, net::IpAddr};
+    ///
+    /// use socks::{async_trait, common::Resolver, v5::socks::Socks};
+    /// # use socks::{common::{Context, Decision}, v5::{client::{Greeting, Request}, server::Choice, socks::Handler, Reply}};
+    /// # struct Example;
+    /// # #[async_trait]
+    /// # impl Handler for Example {
+    /// #     async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> { Ok(Choice::default()) }
+    /// #     async fn request(&self, _: &Context, _: Request) -> Result<Decision<Reply>, Error> { Ok(Decision::Grant) }
+    /// # }
+    ///
+    /// /// Resolver sending every domain name to an internal gateway.
+    /// struct GatewayResolver;
+    ///
+    /// #[async_trait]
+    /// impl Resolver for GatewayResolver {
+    ///     async fn resolve(&self, _: &str) -> Result<Vec<IpAddr>, Error> {
+    ///         Ok(vec![IpAddr::from([10, 0, 0, 1])])
+    ///     }
+    /// }
+    ///
+    /// let server = Socks::new(Example).with_resolver(GatewayResolver);
+    /// ```
+    pub fn with_resolver(mut self, resolver: impl Resolver) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Sets the timeouts, limits and socket options of the server, which are the ones of
+    /// [`Config::default`] by default.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// Terminates TLS on the client connections before the SOCKS handshake, with the
+    /// configuration built by [`server_config`](crate::common::tls::server_config) or
+    /// [`server_config_with_client_auth`](crate::common::tls::server_config_with_client_auth).
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: ServerConfig) -> Self {
+        self.tls = Some(TlsAcceptor::from(Arc::new(config)));
+        self
+    }
+
+    pub async fn listen(&self, addr: impl ToSocketAddrs) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr).await?;
+
+        self.serve(listener).await
+    }
+
+    /// Listens for connections until the shutdown future completes, then stops accepting them
+    /// and gives the active ones the shutdown timeout of the configuration to finish, after which
+    /// the remaining ones are closed.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # async fn example(server: socks::socks::Socks) -> Result<(), std::io::Error> {
+    /// use tokio::sync::oneshot;
+    ///
+    /// let (shutdown, signal) = oneshot::channel::<()>();
+    ///
+    /// // The sender is kept by whatever decides the server is done, e.g. a signal handler.
+    /// let stats = server
+    ///     .listen_with_shutdown("127.0.0.1:1080", async {
+    ///         signal.await.ok();
+    ///     })
+    ///     .await?;
+    ///
+    /// println!("{} connections drained, {} closed", stats.drained, stats.aborted);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn listen_with_shutdown(
+        &self,
+        addr: impl ToSocketAddrs,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<ShutdownStats, Error> {
+        let listener = TcpListener::bind(addr).await?;
+
+        self.serve_with_shutdown(listener, shutdown).await
+    }
+
+    /// Serves the connections accepted on a listener bound by the caller, such as one inherited
+    /// through socket activation.
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), Error> {
+        self.serve_with_shutdown(listener, future::pending())
+            .await
+            .map(|_| ())
+    }
+
+    /// Serves the connections accepted on a listener bound by the caller until the shutdown
+    /// future completes, as [`Server::listen_with_shutdown`] does.
+    pub async fn serve_with_shutdown(
+        &self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<ShutdownStats, Error> {
+        let local_addr = listener.local_addr()?;
+        debug!(local_addr = %local_addr, "server listening for connections");
+
+        self.run(Listener::Tcp(listener), shutdown).await
+    }
+
+    /// Serves the connections accepted on a Unix domain socket bound by the caller, whose file
+    /// permissions control which local users can connect.
+    ///
+    /// The [`Context`] given to the handler carries the credentials of the client process in
+    /// place of its address.
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, listener: UnixListener) -> Result<(), Error> {
+        self.serve_unix_with_shutdown(listener, future::pending())
+            .await
+            .map(|_| ())
+    }
+
+    /// Serves the connections accepted on a Unix domain socket bound by the caller until the
+    /// shutdown future completes, as [`Server::listen_with_shutdown`] does.
+    #[cfg(unix)]
+    pub async fn serve_unix_with_shutdown(
+        &self,
+        listener: UnixListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<ShutdownStats, Error> {
+        let local_addr = listener.local_addr()?;
+        debug!(local_addr = ?local_addr, "server listening for connections");
+
+        self.run(Listener::Unix(listener), shutdown).await
+    }
+
+    /// Serves a single connection accepted by the caller, over any stream that is reliable and
+    /// ordered, such as a Unix domain socket or an in-memory duplex stream.
+    ///
+    /// The client and the local address are the ones set on the [`Context`] given to the handler,
+    /// and the local address is where the sockets for BIND requests are opened, along with the
+    /// ones for the UDP ASSOCIATE requests of SOCKS5 clients, as SOCKS4 has no UDP support.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::{io::Error, net::SocketAddr};
+    ///
+    /// use socks::{
+    ///     async_trait,
+    ///     common::{Context, Decision},
+    ///     v5::{
+    ///         client::{Greeting, Request},
+    ///         server::Choice,
+    ///         socks::{Handler, Socks},
+    ///         stream::{Auth, Socks5Stream},
+    ///         Reply,
+    ///     },
+    /// };
+    ///
+    /// struct Deny;
+    ///
+    /// #[async_trait]
+    /// impl Handler for Deny {
+    ///     async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> {
+    ///         Ok(Choice::default())
+    ///     }
+    ///
+    ///     async fn request(&self, _: &Context, _: Request) -> Result<Decision<Reply>, Error> {
+    ///         Ok(Decision::Reject(Reply::ConnectionNotAllowedByRuleset))
+    ///     }
+    /// }
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let (client, stream) = tokio::io::duplex(1024);
+    /// let addr: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+    ///
+    /// tokio::spawn(async move { Socks::new(Deny).serve_stream(stream, addr, addr).await });
+    ///
+    /// let result = Socks5Stream::connect_with(client, ("example.com", 80), Auth::None).await;
+    /// assert!(result.is_err());
+    /// # }
+    /// ```
+    pub async fn serve_stream(
+        &self,
+        stream: impl Stream + 'static,
+        peer: impl Into<Peer>,
+        local_addr: SocketAddr,
+    ) {
+        self.serve_connection(&self.dialer(), Box::new(stream), peer.into(), local_addr)
+            .await
+    }
+
+    /// Accepts connections on the listener until the shutdown future completes.
+    async fn run(
+        &self,
+        listener: Listener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<ShutdownStats, Error> {
+        let dialer = self.dialer();
+
+        listener::run(
+            listener,
+            &self.config,
+            shutdown,
+            |stream, peer, local_addr| self.serve_connection(&dialer, stream, peer, local_addr),
+        )
+        .await
+    }
+
+    /// Serves a client connection, terminating TLS first when it is enabled.
+    fn serve_connection(
+        &self,
+        dialer: &Arc<dyn Dialer>,
+        stream: BoxStream,
+        peer: Peer,
+        local_addr: SocketAddr,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let service = Arc::clone(&self.service);
+        let dialer = Arc::clone(dialer);
+        let resolver = Arc::clone(&self.resolver);
+        let config = Arc::clone(&self.config);
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+
+        async move {
+            #[allow(unused_mut)]
+            let mut context = Context::new(peer, local_addr, P::VERSION).with_resolver(resolver);
+
+            #[cfg(feature = "tls")]
+            let stream = match tls {
+                Some(acceptor) => {
+                    match accept_tls(&acceptor, &config, stream, &mut context).await {
+                        Some(stream) => stream,
+                        None => return,
+                    }
+                }
+                None => stream,
+            };
+
+            let connection = Connection::new(stream).with_timeout(config.handshake_timeout);
+
+            service.serve(dialer, config, connection, context).await
+        }
+    }
+
+    /// Returns the dialer set on the server, or a [`DirectDialer`] resolving with its resolver
+    /// and setting the socket options of its configuration.
+    fn dialer(&self) -> Arc<dyn Dialer> {
+        if let Some(dialer) = &self.dialer {
+            return Arc::clone(dialer);
+        }
+
+        let mut dialer = DirectDialer::new()
+            .with_resolver(Arc::clone(&self.resolver))
+            .with_nodelay(self.config.nodelay);
+
+        if let Some(time) = self.config.keepalive {
+            dialer = dialer.with_keepalive(time);
+        }
+
+        Arc::new(dialer)
+    }
+}
+
+/// Performs the TLS handshake with the client, setting the certificate chain it authenticated
+/// with, and the identity of its certificate, on the context.
+#[cfg(feature = "tls")]
+async fn accept_tls(
+    acceptor: &TlsAcceptor,
+    config: &Config,
+    stream: BoxStream,
+    context: &mut Context,
+) -> Option<BoxStream> {
+    let accept = acceptor.accept(stream);
+    let stream = match super::timeout(config.handshake_timeout, accept, "TLS handshake").await {
+        Ok(s) => s,
+        Err(e) => {
+            error!(peer = %context.peer, error = %e, "TLS handshake with client failed");
+
+            return None;
+        }
+    };
+
+    context.peer_certificates = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .map(|certificates| certificates.iter().map(|c| c.to_vec()).collect());
+
+    trace!(peer = %context.peer, client_auth = context.peer_certificates.is_some(), "TLS handshake with client completed");
+
+    if let Some(certificate) = context.peer_certificates.as_ref().and_then(|c| c.first()) {
+        match tls::parse_certificate(certificate) {
+            Ok(certificate) => context.identity = Some(Identity::Certificate(certificate)),
+            // NOTE: The certificate was verified by the server, so it is only kept without an
+            // identity when its names cannot be read.
+            Err(e) => warn!(peer = %context.peer, error = %e, "failed to read client certificate"),
+        }
+    }
+
+    Some(Box::new(stream))
+}
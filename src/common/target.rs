@@ -0,0 +1,163 @@
+//! Target addresses, as requested by clients of a SOCKS proxy server.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// Address of the host a SOCKS proxy server connects to on behalf of a client.
+///
+/// # Example
+///
+/// ```rust
+/// use socks::common::Target;
+///
+/// let target = Target::from(("example.com", 80));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// IPv4 or IPv6 socket address.
+    Addr(SocketAddr),
+    /// Domain name and port, resolved by the proxy server.
+    Domain(String, u16),
+}
+
+impl Target {
+    /// Returns the host of the target, without its port.
+    pub fn destination(&self) -> Destination {
+        match self {
+            Target::Addr(addr) => Destination::from(addr.ip()),
+            Target::Domain(domain, _) => Destination::Domain(domain.clone()),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Target::Addr(addr) => addr.port(),
+            Target::Domain(_, port) => *port,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Self {
+        Target::Addr(addr)
+    }
+}
+
+impl From<(IpAddr, u16)> for Target {
+    fn from((ip, port): (IpAddr, u16)) -> Self {
+        Target::Addr(SocketAddr::new(ip, port))
+    }
+}
+
+impl From<(Ipv4Addr, u16)> for Target {
+    fn from((ip, port): (Ipv4Addr, u16)) -> Self {
+        Target::Addr(SocketAddr::new(IpAddr::V4(ip), port))
+    }
+}
+
+impl From<(Ipv6Addr, u16)> for Target {
+    fn from((ip, port): (Ipv6Addr, u16)) -> Self {
+        Target::Addr(SocketAddr::new(IpAddr::V6(ip), port))
+    }
+}
+
+impl From<(Destination, u16)> for Target {
+    fn from((destination, port): (Destination, u16)) -> Self {
+        match destination {
+            Destination::Ipv4(ip) => Target::from((ip, port)),
+            Destination::Ipv6(ip) => Target::from((ip, port)),
+            Destination::Domain(domain) => Target::Domain(domain, port),
+        }
+    }
+}
+
+impl From<(&str, u16)> for Target {
+    fn from((host, port): (&str, u16)) -> Self {
+        Target::from((host.to_string(), port))
+    }
+}
+
+impl From<(String, u16)> for Target {
+    /// Creates a target from a host, which is parsed as an IP address when possible.
+    fn from((host, port): (String, u16)) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Target::Addr(SocketAddr::new(ip, port)),
+            Err(_) => Target::Domain(host, port),
+        }
+    }
+}
+
+/// Host a client asked the server to reach, as sent on its request.
+///
+/// Domain names are kept as sent, so handlers can decide on them and logs show them, while the
+/// server resolves them only when it connects to the target.
+///
+/// # Example
+///
+/// ```rust
+/// use socks::common::Destination;
+///
+/// fn is_internal(destination: &Destination) -> bool {
+///     match destination {
+///         Destination::Ipv4(ip) => ip.is_private(),
+///         Destination::Ipv6(ip) => ip.is_loopback(),
+///         Destination::Domain(domain) => domain.ends_with(".internal"),
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// Domain name, not resolved.
+    Domain(String),
+}
+
+impl Destination {
+    /// Returns the domain name, or `None` when the destination is an IP address.
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            Destination::Domain(domain) => Some(domain),
+            _ => None,
+        }
+    }
+
+    /// Returns the IP address, or `None` when the destination is a domain name.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Destination::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            Destination::Ipv6(ip) => Some(IpAddr::V6(*ip)),
+            Destination::Domain(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Ipv4(ip) => write!(f, "{}", ip),
+            Destination::Ipv6(ip) => write!(f, "{}", ip),
+            Destination::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
+impl From<IpAddr> for Destination {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Destination::Ipv4(ip),
+            IpAddr::V6(ip) => Destination::Ipv6(ip),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! TLS between the clients and the SOCKS5 server, so the handshake, including the credentials of
+//! the username/password method, is not sent in cleartext.
+//!
+//! The configurations are built with the `ring` cryptography provider of [`rustls`], which is
+//! re-exported along with the acceptor and connector of [`tokio_rustls`].
+
+use std::{
+    fs::File,
+    io::{BufReader, Error, ErrorKind},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+
+pub use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+use super::Certificate;
+
+/// Loads the certificates from a PEM file.
+pub fn load_certificates(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+/// Loads the first private key from a PEM file.
+pub fn load_private_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no private key found"))
+}
+
+/// Creates the configuration of a server presenting the certificate chain and key in the PEM
+/// files.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use socks::{common::tls, v5::socks::Socks};
+/// # use std::io::Error;
+/// # use socks::{async_trait, common::{Context, Decision}, v5::{client::{Greeting, Request}, server::Choice, socks::Handler, Reply}};
+/// # struct Example;
+/// # #[async_trait]
+/// # impl Handler for Example {
+/// #     async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> { Ok(Choice::default()) }
+/// #     async fn request(&self, _: &Context, _: Request) -> Result<Decision<Reply>, Error> { Ok(Decision::Grant) }
+/// # }
+///
+/// # fn example() -> Result<(), Error> {
+/// let config = tls::server_config("proxy.crt", "proxy.key")?;
+/// let server = Socks::new(Example).with_tls(config);
+/// # Ok(())
+/// # }
+/// ```
+pub fn server_config(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<ServerConfig, Error> {
+    ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(load_certificates(cert_path)?, load_private_key(key_path)?)
+        .map_err(tls_error)
+}
+
+/// Creates the configuration of a server presenting the certificate chain and key in the PEM
+/// files, which requires the clients to authenticate with a certificate issued by one of the
+/// authorities in the CA PEM file.
+///
+/// The certificate chain each client is verified with is set on the
+/// [`Context`](crate::common::Context) given to the handler, along with its
+/// [`Identity::Certificate`](crate::common::Identity::Certificate).
+pub fn server_config_with_client_auth(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+    ca_path: impl AsRef<Path>,
+) -> Result<ServerConfig, Error> {
+    let verifier =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(ca_path)?), provider())
+            .build()
+            .map_err(tls_error)?;
+
+    ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(load_certificates(cert_path)?, load_private_key(key_path)?)
+        .map_err(tls_error)
+}
+
+/// Creates the configuration of a client trusting the authorities in the CA PEM file.
+pub fn client_config(ca_path: impl AsRef<Path>) -> Result<ClientConfig, Error> {
+    Ok(ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(root_store(ca_path)?)
+        .with_no_client_auth())
+}
+
+/// Creates the configuration of a client trusting the authorities in the CA PEM file, which
+/// authenticates with the certificate chain and key in the PEM files.
+pub fn client_config_with_certificate(
+    ca_path: impl AsRef<Path>,
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<ClientConfig, Error> {
+    ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(root_store(ca_path)?)
+        .with_client_auth_cert(load_certificates(cert_path)?, load_private_key(key_path)?)
+        .map_err(tls_error)
+}
+
+/// Reads the subject and the subject alternative names of a DER-encoded certificate.
+pub fn parse_certificate(der: &[u8]) -> Result<Certificate, Error> {
+    let (_, certificate) = X509Certificate::from_der(der).map_err(tls_error)?;
+    let subject = certificate.subject();
+
+    let common_name = subject
+        .iter_common_name()
+        .next()
+        .and_then(|name| name.as_str().ok())
+        .map(str::to_string);
+
+    let mut alt_names = Vec::new();
+
+    if let Some(extension) = certificate.subject_alternative_name().map_err(tls_error)? {
+        for name in &extension.value.general_names {
+            match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => alt_names.push(name.to_string()),
+                GeneralName::IPAddress(octets) => {
+                    alt_names.extend(ip_addr(octets).map(|addr| addr.to_string()))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(Certificate {
+        subject: subject.to_string(),
+        common_name,
+        alt_names,
+    })
+}
+
+/// Returns the IP address of a subject alternative name, when it has the length of one.
+fn ip_addr(octets: &[u8]) -> Option<IpAddr> {
+    if let Ok(octets) = <[u8; 4]>::try_from(octets) {
+        return Some(IpAddr::from(octets));
+    }
+
+    <[u8; 16]>::try_from(octets).ok().map(IpAddr::from)
+}
+
+/// Returns the cryptography provider the configurations are built with.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Loads the certificates of the trusted authorities from a PEM file.
+fn root_store(ca_path: impl AsRef<Path>) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+
+    for certificate in load_certificates(ca_path)? {
+        roots.add(certificate).map_err(tls_error)?;
+    }
+
+    Ok(roots)
+}
+
+fn tls_error(error: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::new(ErrorKind::InvalidData, error)
+}
@@ -0,0 +1,120 @@
+//! Errors of the SOCKS protocol.
+
+use std::{fmt, io};
+
+use crate::{v4, v5};
+
+/// Error on parsing, or exchanging, the packets of the SOCKS protocol.
+///
+/// It converts into an [`io::Error`] whose kind matches the variant, and whose payload is the
+/// error itself, so it can be recovered with [`io::Error::get_ref`] and a downcast.
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error on the stream.
+    Io(io::Error),
+    /// The packet is truncated or its fields are inconsistent.
+    Malformed(&'static str),
+    /// The packet carries a version that is not supported.
+    UnsupportedVersion(u8),
+    /// The request carries a command that is not supported.
+    UnsupportedCommand(u8),
+    /// The packet carries an address type that is not supported.
+    UnsupportedAddressType(u8),
+    /// The response carries an unknown reply code.
+    UnknownReply(u8),
+    /// None of the authentication methods offered is acceptable.
+    NoAcceptableAuthMethod,
+    /// The credentials were rejected.
+    AuthenticationFailed,
+    /// The domain name could not be resolved to an address.
+    Resolution(String),
+    /// A protocol state machine was asked for a step its current state does not allow.
+    InvalidState(&'static str),
+    /// The field is too long for the packet to carry its length.
+    TooLong(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Malformed(reason) => write!(f, "malformed packet: {}", reason),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported version: {:#04x}", version)
+            }
+            Error::UnsupportedCommand(command) => {
+                write!(f, "unsupported command: {:#04x}", command)
+            }
+            Error::UnsupportedAddressType(kind) => {
+                write!(f, "unsupported address type: {:#04x}", kind)
+            }
+            Error::UnknownReply(code) => write!(f, "unknown reply code: {:#04x}", code),
+            Error::NoAcceptableAuthMethod => write!(f, "no acceptable authentication method"),
+            Error::AuthenticationFailed => write!(f, "authentication failed"),
+            Error::Resolution(domain) => write!(f, "failed to resolve {}", domain),
+            Error::InvalidState(step) => write!(f, "invalid state for {}", step),
+            Error::TooLong(field) => write!(f, "{} too long", field),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Reply, other than granted, sent by an upstream proxy to a request.
+///
+/// It is the payload of the errors returned by the client streams when a request is not granted,
+/// so the reply can be recovered with [`io::Error::get_ref`] and a downcast, whatever the version
+/// of the proxy.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplyError {
+    V4(v4::Reply),
+    V5(v5::Reply),
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplyError::V4(reply) => write!(f, "proxy replied {:?}", reply),
+            ReplyError::V5(reply) => write!(f, "proxy replied {:?}", reply),
+        }
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match &error {
+            Error::Io(e) => e.kind(),
+            Error::Malformed(_) | Error::UnsupportedVersion(_) | Error::UnknownReply(_) => {
+                io::ErrorKind::InvalidData
+            }
+            Error::UnsupportedCommand(_) | Error::UnsupportedAddressType(_) => {
+                io::ErrorKind::Unsupported
+            }
+            Error::NoAcceptableAuthMethod | Error::AuthenticationFailed => {
+                io::ErrorKind::PermissionDenied
+            }
+            Error::Resolution(_) => io::ErrorKind::NotFound,
+            Error::InvalidState(_) | Error::TooLong(_) => io::ErrorKind::InvalidInput,
+        };
+
+        match error {
+            Error::Io(e) => e,
+            error => io::Error::new(kind, error),
+        }
+    }
+}
@@ -14,9 +14,16 @@ anonymous internet communication.
 */
 
 pub mod common;
+pub mod error;
+pub mod socks;
 pub mod v4;
 pub mod v5;
 
+pub use error::{Error, ReplyError};
+
+/// Attribute used to implement the asynchronous handler traits.
+pub use async_trait::async_trait;
+
 /// Versions.
 ///
 /// # Example
@@ -26,7 +33,7 @@ pub mod v5;
 ///
 /// let version: u8 = Version::V4 as u8;
 /// ```
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Version {
     Invalid = 0x00,
     V4 = 0x04,
@@ -71,11 +78,11 @@ pub enum Command {
 
 impl From<u8> for Command {
     fn from(value: u8) -> Self {
-        return match value {
+        match value {
             0x01 => Command::Connect,
             0x02 => Command::Bind,
             0x03 => Command::Associate,
             _ => Self::Invalid,
-        };
+        }
     }
 }
@@ -0,0 +1,107 @@
+//! SOCKS server for every supported version.
+//!
+//! The version of each client is detected from the first byte it sends, so SOCKS4, SOCKS4a and
+//! SOCKS5 clients can share the same listener.
+
+use std::{future::Future, io::ErrorKind, sync::Arc};
+
+use tracing::{debug, error, trace, warn};
+
+use crate::{
+    common::{Config, Connection, Context, Dialer, Server, Service},
+    v4, v5, Version,
+};
+
+/// Handler for clients of every supported version.
+///
+/// It is implemented for any type that implements both the SOCKS4 and the SOCKS5 handlers, whose
+/// methods are the version-specific hooks.
+pub trait Handler: v4::socks::Handler + v5::socks::Handler {}
+
+impl<T: v4::socks::Handler + v5::socks::Handler> Handler for T {}
+
+/// SOCKS server for every supported version, serving its clients with a [`Handler`].
+///
+/// The methods shared by every server are documented on [`Server`].
+pub type Socks = Server<AnyVersion>;
+
+/// Service of the servers for every supported version, which detect the version of each client.
+pub struct AnyVersion {
+    v4: Arc<dyn v4::socks::Handler>,
+    v5: Arc<dyn v5::socks::Handler>,
+}
+
+impl Socks {
+    pub fn new(internal: impl Handler) -> Self {
+        debug!("initializing server with custom handler");
+        let handler = Arc::new(internal);
+
+        Server::from_service(AnyVersion {
+            v4: handler.clone(),
+            v5: handler,
+        })
+    }
+}
+
+impl Service for AnyVersion {
+    // NOTE: The version is set on the context once the first byte of the client is peeked.
+    const VERSION: Version = Version::Invalid;
+
+    fn serve(
+        &self,
+        dialer: Arc<dyn Dialer>,
+        config: Arc<Config>,
+        connection: Connection,
+        context: Context,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        serve(
+            Arc::clone(&self.v4),
+            Arc::clone(&self.v5),
+            dialer,
+            config,
+            connection,
+            context,
+        )
+    }
+}
+
+/// Detects the version of the client and serves it with the handler for that version.
+async fn serve(
+    v4: Arc<dyn v4::socks::Handler>,
+    v5: Arc<dyn v5::socks::Handler>,
+    dialer: Arc<dyn Dialer>,
+    config: Arc<Config>,
+    mut connection: Connection,
+    mut context: Context,
+) {
+    let peer = context.peer;
+
+    // NOTE: The first byte of both the SOCKS4 request and the SOCKS5 greeting is the version
+    // number, so it is peeked without being consumed.
+    let version = match connection.peek().await {
+        Ok(version) => Version::from(version),
+        Err(crate::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+            trace!(peer = %peer, "stream closed before version was received");
+
+            return;
+        }
+        Err(e) => {
+            error!(peer = %peer, error = %e, "failed to read version from client");
+
+            return;
+        }
+    };
+
+    trace!(peer = %peer, ?version, "detected client version");
+
+    // NOTE: The context is created before the version is known, and completed once it is.
+    context.version = version;
+
+    match version {
+        Version::V4 => v4::socks::serve(v4, dialer, config, connection, context).await,
+        Version::V5 => v5::socks::serve(v5, dialer, config, connection, context).await,
+        Version::Invalid => {
+            warn!(peer = %peer, "unsupported version, closing connection");
+        }
+    }
+}
@@ -1,8 +1,11 @@
 //! SOCKS request packet, and utilities.
 
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 
-use crate::{Command, Version};
+use crate::{
+    common::{Destination, Frame, Target},
+    Command, Version,
+};
 
 /// SOCKS4 request packet.
 #[derive(Debug, Clone)]
@@ -17,41 +20,180 @@ pub struct Request {
     pub addr: [u8; 4],
     /// The user ID string, variable length, null-terminated.
     pub id: String,
+    /// The domain name of the host to contact, variable length, null-terminated.
+    ///
+    /// Only present on SOCKS4a requests, which set the destination address to `0.0.0.x`, with
+    /// `x` nonzero.
+    pub domain: Option<String>,
 }
 
 impl Request {
+    /// Creates a request for the target, which is sent as a SOCKS4a request when it is a domain
+    /// name.
+    ///
+    /// Returns `None` when the target is an IPv6 address, which SOCKS4 cannot carry.
+    pub fn new(command: Command, target: &Target, id: &str) -> Option<Self> {
+        let (addr, domain) = match target {
+            Target::Addr(addr) => match addr.ip() {
+                IpAddr::V4(ip) => (ip.octets(), None),
+                IpAddr::V6(ip) => (ip.to_ipv4_mapped()?.octets(), None),
+            },
+            Target::Domain(domain, _) => ([0x00, 0x00, 0x00, 0x01], Some(domain.clone())),
+        };
+
+        Some(Request {
+            version: 0x04,
+            command: command as u8,
+            port: target.port().to_be_bytes(),
+            addr,
+            id: id.to_string(),
+            domain,
+        })
+    }
+
     pub fn get_version(&self) -> Version {
-        return Version::from(self.version);
+        Version::from(self.version)
     }
 
     pub fn get_command(&self) -> Command {
-        return Command::from(self.command);
+        Command::from(self.command)
     }
 
     pub fn get_port(&self) -> u16 {
-        return u16::from_be_bytes(self.port);
+        u16::from_be_bytes(self.port)
+    }
+
+    /// Returns true if the request is a SOCKS4a request, which carries a domain name instead of
+    /// an IP address.
+    pub fn is_socks4a(&self) -> bool {
+        is_socks4a_addr(&self.addr)
+    }
+
+    pub fn get_domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Returns the requested host, without resolving the domain name of SOCKS4a requests.
+    pub fn get_destination(&self) -> Option<Destination> {
+        if !self.is_socks4a() {
+            return Some(Destination::Ipv4(Ipv4Addr::from(self.addr)));
+        }
+
+        Some(Destination::Domain(self.domain.clone()?))
     }
 
-    pub fn get_addr(&self) -> IpAddr {
-        return IpAddr::from(self.addr);
+    /// Returns the requested target, without resolving the domain name of SOCKS4a requests.
+    pub fn get_target(&self) -> Option<Target> {
+        Some(Target::from((self.get_destination()?, self.get_port())))
+    }
+
+    /// Returns the IP address, or `None` on SOCKS4a requests, whose domain name is resolved by
+    /// the [`Resolver`](crate::common::Resolver) of the server.
+    pub fn get_addr(&self) -> Option<IpAddr> {
+        if self.is_socks4a() {
+            return None;
+        }
+
+        Some(IpAddr::from(self.addr))
+    }
+}
+
+/// Checks if the address is the `0.0.0.x` sentinel, with `x` nonzero, used by SOCKS4a.
+fn is_socks4a_addr(addr: &[u8; 4]) -> bool {
+    addr[0] == 0x00 && addr[1] == 0x00 && addr[2] == 0x00 && addr[3] != 0x00
+}
+
+/// Reads a null-terminated string from the buffer, returning it and the position after the null
+/// byte, or `None` when no null byte is found.
+fn read_null_terminated(buffer: &[u8]) -> Option<(String, usize)> {
+    let end = buffer.iter().position(|byte| *byte == 0x00)?;
+
+    Some((String::from_utf8_lossy(&buffer[..end]).to_string(), end + 1))
+}
+
+impl Frame for Request {
+    fn frame_len(buffer: &[u8]) -> Result<Option<usize>, crate::Error> {
+        let addr: [u8; 4] = match buffer.get(4..8) {
+            Some(addr) => [addr[0], addr[1], addr[2], addr[3]],
+            None => return Ok(None),
+        };
+
+        let id_size = match read_null_terminated(&buffer[8..]) {
+            Some((_, size)) => size,
+            None => return Ok(None),
+        };
+
+        if !is_socks4a_addr(&addr) {
+            return Ok(Some(8 + id_size));
+        }
+
+        Ok(read_null_terminated(&buffer[8 + id_size..])
+            .map(|(_, domain_size)| 8 + id_size + domain_size))
     }
 }
 
-impl From<&[u8]> for Request {
-    fn from(buffer: &[u8]) -> Self {
-        // TODO: Avoid panics by checking the length of the buffer.
-        return Request {
+impl TryFrom<&[u8]> for Request {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        if buffer.len() < 8 {
+            return Err(crate::Error::Malformed("request too short"));
+        }
+
+        if buffer[0] != 0x04 {
+            return Err(crate::Error::UnsupportedVersion(buffer[0]));
+        }
+
+        if !matches!(Command::from(buffer[1]), Command::Connect | Command::Bind) {
+            return Err(crate::Error::UnsupportedCommand(buffer[1]));
+        }
+
+        let addr = [buffer[4], buffer[5], buffer[6], buffer[7]];
+
+        let (id, id_size) = read_null_terminated(&buffer[8..])
+            .ok_or(crate::Error::Malformed("user ID is not null-terminated"))?;
+        let domain = if is_socks4a_addr(&addr) {
+            let (domain, _) = read_null_terminated(&buffer[8 + id_size..]).ok_or(
+                crate::Error::Malformed("domain name is not null-terminated"),
+            )?;
+
+            Some(domain)
+        } else {
+            None
+        };
+
+        Ok(Request {
             version: buffer[0],
             command: buffer[1],
             port: [buffer[2], buffer[3]],
-            addr: [buffer[4], buffer[5], buffer[6], buffer[7]],
-            id: String::from_utf8_lossy(&buffer[7..]).to_string(),
-        };
+            addr,
+            id,
+            domain,
+        })
+    }
+}
+
+impl TryFrom<Vec<u8>> for Request {
+    type Error = crate::Error;
+
+    fn try_from(buffer: Vec<u8>) -> Result<Self, Self::Error> {
+        Request::try_from(&buffer[..])
     }
 }
 
-impl From<Vec<u8>> for Request {
-    fn from(buffer: Vec<u8>) -> Self {
-        return Request::from(&buffer[..]);
+impl From<Request> for Vec<u8> {
+    fn from(request: Request) -> Self {
+        let mut buffer = vec![request.version, request.command];
+        buffer.extend_from_slice(&request.port);
+        buffer.extend_from_slice(&request.addr);
+        buffer.extend_from_slice(request.id.as_bytes());
+        buffer.push(0x00);
+
+        if let Some(domain) = request.domain {
+            buffer.extend_from_slice(domain.as_bytes());
+            buffer.push(0x00);
+        }
+
+        buffer
     }
 }
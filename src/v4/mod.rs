@@ -12,6 +12,8 @@
 pub mod client;
 pub mod server;
 pub mod socks;
+pub mod state;
+pub mod stream;
 
 /// Reply code.
 ///
@@ -22,7 +24,7 @@ pub mod socks;
 ///
 /// let reply: u8 = Reply::Granted as u8;
 /// ```
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Reply {
     /// Request granted.
     Granted = 0x5A,
@@ -34,8 +36,39 @@ pub enum Reply {
     FailedClientNotConfirmed = 0x5D,
 }
 
-impl Into<u8> for Reply {
-    fn into(self) -> u8 {
-        return self as u8;
+impl From<Reply> for u8 {
+    fn from(reply: Reply) -> Self {
+        reply as u8
+    }
+}
+
+impl TryFrom<u8> for Reply {
+    type Error = crate::Error;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0x5A => Ok(Reply::Granted),
+            0x5B => Ok(Reply::RejectOrFailed),
+            0x5C => Ok(Reply::FailedClientNotRunning),
+            0x5D => Ok(Reply::FailedClientNotConfirmed),
+            _ => Err(crate::Error::UnknownReply(byte)),
+        }
+    }
+}
+
+impl From<&std::io::Error> for Reply {
+    /// Maps the error of a failed connection to a target onto a reply.
+    ///
+    /// SOCKS4 has a single reply code for failures that are not about identd, so every error is
+    /// reported as [`Reply::RejectOrFailed`], except the reply of an upstream SOCKS4 proxy, carried
+    /// by a [`ReplyError`](crate::ReplyError), which is kept as it is.
+    fn from(error: &std::io::Error) -> Self {
+        match error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<crate::ReplyError>())
+        {
+            Some(crate::ReplyError::V4(reply)) => reply.clone(),
+            _ => Reply::RejectOrFailed,
+        }
     }
 }
@@ -1,3 +1,7 @@
+use std::net::SocketAddrV4;
+
+use crate::common::Frame;
+
 use super::Reply;
 
 /// The size of the Response packet sent by SOCKS proxy server.
@@ -10,12 +14,12 @@ pub struct Response {
     pub version: u8,
     /// Reply code.
     ///
-    /// This is the only field that is really important. The SOCKS4 protocol specifies that the
-    /// values of the others bytes should be ignored on the response.
+    /// This is the only field that is really important on CONNECT. The SOCKS4 protocol specifies
+    /// that the values of the others bytes should be ignored on its response.
     pub reply: u8,
-    /// Destination port, meaningful if granted in BIND, otherwise ignore. Ignored on response.
+    /// Destination port, meaningful if granted in BIND, otherwise ignore.
     pub port: [u8; 2],
-    /// Destination IP, as above – the ip:port the client should bind to. Ignored on response.
+    /// Destination IP, as above – the ip:port the client should bind to.
     pub ip: [u8; 4],
 }
 
@@ -23,24 +27,62 @@ impl Response {
     pub fn new(reply: Reply) -> Self {
         // NOTE: The field `reply`  is the only field that is really important. The SOCKS4 protocol specifies that the
         // values of the others bytes should be ignored on the response.
-        return Response {
+        Response {
             version: 0x00,
             reply: reply.into(),
             port: [0x00, 0x00],
             ip: [0x00, 0x00, 0x00, 0x00],
-        };
+        }
+    }
+
+    /// Creates a response carrying a socket address, as the BIND replies do.
+    pub fn with_addr(reply: Reply, addr: SocketAddrV4) -> Self {
+        Response {
+            version: 0x00,
+            reply: reply.into(),
+            port: addr.port().to_be_bytes(),
+            ip: addr.ip().octets(),
+        }
     }
 }
 
-const RESPONSE_REPLY_BUFFER_POSITION: usize = 1;
+impl From<[u8; SOCKS4_RESPONSE_SIZE]> for Response {
+    fn from(buffer: [u8; SOCKS4_RESPONSE_SIZE]) -> Self {
+        Response {
+            version: buffer[0],
+            reply: buffer[1],
+            port: [buffer[2], buffer[3]],
+            ip: [buffer[4], buffer[5], buffer[6], buffer[7]],
+        }
+    }
+}
 
-impl Into<Vec<u8>> for Response {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = [0 as u8; 8];
+impl Frame for Response {
+    fn frame_len(_: &[u8]) -> Result<Option<usize>, crate::Error> {
+        Ok(Some(SOCKS4_RESPONSE_SIZE))
+    }
+}
+
+impl TryFrom<&[u8]> for Response {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        let buffer: [u8; SOCKS4_RESPONSE_SIZE] = buffer
+            .try_into()
+            .map_err(|_| crate::Error::Malformed("response must be 8 bytes"))?;
+
+        Ok(Response::from(buffer))
+    }
+}
 
-        buffer[RESPONSE_REPLY_BUFFER_POSITION] = self.reply;
-        // The others fields are ignored due to RFC.
+impl From<Response> for Vec<u8> {
+    fn from(response: Response) -> Self {
+        let mut buffer = Vec::with_capacity(SOCKS4_RESPONSE_SIZE);
+        buffer.push(response.version);
+        buffer.push(response.reply);
+        buffer.extend_from_slice(&response.port);
+        buffer.extend_from_slice(&response.ip);
 
-        return buffer.to_vec();
+        buffer
     }
 }
@@ -1,155 +1,484 @@
-use std::{io::Error, net::SocketAddr, sync::Arc};
-
-use tokio::{
-    net::{TcpListener, TcpStream, ToSocketAddrs},
-    task,
+use std::{
+    future::Future,
+    io::Error,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    sync::Arc,
 };
-use tracing::{debug, error, span, trace, warn, Instrument, Level};
+
+use tokio::{net::TcpListener, select};
+use tracing::{debug, error, field, span, trace, warn, Instrument, Level};
 
 use crate::{
-    common::{relay, Connection},
-    v4::{client::Request, server::Response},
-    Command,
+    async_trait,
+    common::{
+        self, relay, Config, Connection, Context, Decision, Dialer, Identity, Server, Service,
+        Target,
+    },
+    v4::{
+        client::Request,
+        server::Response,
+        state::{self, ServerEvent},
+    },
+    Command, Version,
 };
 
 use super::Reply;
 
+/// Handler of SOCKS4 and SOCKS4a clients.
+///
+/// Each method receives the [`Context`] of the client connection it is called for, and is
+/// implemented with the [`async_trait`] attribute.
+#[async_trait]
 pub trait Handler: Send + Sync + 'static {
-    fn request(&self, request: Request) -> Result<Reply, Error>;
+    /// Decides on a request before the server acts on it, granting it as sent, redirecting it to
+    /// another target, or rejecting it with a reply.
+    async fn request(&self, context: &Context, request: Request) -> Result<Decision<Reply>, Error>;
+    /// Decides whether the peer that connected to the port opened by a BIND request is accepted.
+    ///
+    /// The default implementation accepts only a peer whose IP address is the one on the request,
+    /// as the SOCKS4 protocol specifies, or one of the addresses the resolver of the server finds
+    /// for the domain name of a SOCKS4a request.
+    async fn bind(
+        &self,
+        context: &Context,
+        request: Request,
+        peer_addr: SocketAddr,
+    ) -> Result<Reply, Error> {
+        let accepted = match request.get_target() {
+            Some(Target::Addr(addr)) => addr.ip() == peer_addr.ip(),
+            Some(Target::Domain(domain, _)) => context
+                .resolve(&domain)
+                .await
+                .is_ok_and(|addrs| addrs.contains(&peer_addr.ip())),
+            None => false,
+        };
+
+        if accepted {
+            Ok(Reply::Granted)
+        } else {
+            Ok(Reply::RejectOrFailed)
+        }
+    }
 }
 
-pub struct Socks {
+/// SOCKS4 server, serving its SOCKS4 and SOCKS4a clients with a [`Handler`].
+///
+/// The methods shared by every server are documented on [`Server`].
+pub type Socks = Server<Socks4>;
+
+/// Service of the SOCKS4 servers.
+pub struct Socks4 {
     handler: Arc<dyn Handler>,
 }
 
 impl Socks {
     pub fn new(internal: impl Handler) -> Self {
         debug!("initializing server with custom handler");
-        return Socks {
+
+        Server::from_service(Socks4 {
             handler: Arc::new(internal),
-        };
+        })
     }
+}
 
-    pub async fn listen(&self, addr: impl ToSocketAddrs) -> Result<(), Error> {
-        let listener = TcpListener::bind(addr).await?;
-        let local_addr = listener.local_addr()?;
-        debug!(local_addr = %local_addr, "server listening for connections");
+impl Service for Socks4 {
+    const VERSION: Version = Version::V4;
 
-        loop {
-            let (stream, _) = listener.accept().await?;
-            let peer_addr = match stream.peer_addr() {
-                Ok(addr) => addr,
+    fn serve(
+        &self,
+        dialer: Arc<dyn Dialer>,
+        config: Arc<Config>,
+        connection: Connection,
+        context: Context,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        serve(
+            Arc::clone(&self.handler),
+            dialer,
+            config,
+            connection,
+            context,
+        )
+    }
+}
+
+/// Serves a single client connection, from the request to the end of the command.
+pub(crate) async fn serve(
+    handler: Arc<dyn Handler>,
+    dialer: Arc<dyn Dialer>,
+    config: Arc<Config>,
+    connection: Connection,
+    mut context: Context,
+) {
+    let id = context.id;
+    let peer = context.peer;
+    let mut handshake = Handshake::new(connection);
+
+    async move {
+        trace!("spawning new handler task");
+        trace!("processing new client stream");
+
+        // Request phase
+        let request = match handshake.read_request().await {
+            Ok(r) => {
+                trace!(?r, "received request from client");
+                r
+            }
+            Err(crate::Error::Io(e)) => {
+                error!(error = ?e, "failed to read request from client");
+                return;
+            }
+            Err(e) => {
+                error!(error = %e, "invalid request from client");
+
+                if let Err(e) = handshake.refuse(Response::new(Reply::RejectOrFailed)).await {
+                    error!(error = ?e, "error writing invalid request response to stream");
+                }
+
+                return;
+            }
+        };
+
+        debug!(
+            command = ?request.get_command(),
+            destination = ?request.get_destination(),
+            port = request.get_port(),
+            "received request from client"
+        );
+
+        if !request.id.is_empty() {
+            context.identity = Some(Identity::UserId(request.id.clone()));
+        }
+
+        let command = request.get_command();
+        let target = match request.get_target() {
+            Some(t) => t,
+            None => {
+                error!("missing domain name on SOCKS4a request");
+
+                if let Err(e) = handshake.reply(Response::new(Reply::RejectOrFailed)).await {
+                    error!(error = ?e, "error writing invalid request response to stream");
+                }
+
+                return;
+            }
+        };
+
+        trace!(command = ?command, "processing request");
+
+        let span = span!(
+            Level::INFO,
+            "target",
+            target = %target,
+            command = ?command,
+            target_addr = field::Empty
+        );
+
+        async {
+            if !matches!(command, Command::Connect | Command::Bind) {
+                trace!(?command, "unsupported command");
+
+                if let Err(e) = handshake.reply(Response::new(Reply::RejectOrFailed)).await {
+                    error!(error = ?e, "error writing unsupported command response to stream");
+                }
+
+                return;
+            }
+
+            // NOTE: The handler decides on the request before anything is done for it, so no
+            // connection is opened to a target it rejects.
+            trace!("processing request through handler");
+            let decision = match handler.request(&context, request.clone()).await {
+                Ok(d) => {
+                    trace!(decision = ?d, "handler decided on request");
+                    d
+                }
                 Err(e) => {
-                    error!(error = %e, "failed to get peer address for incoming connection");
-                    continue;
+                    error!(error = ?e, "handler failed to decide on request");
+
+                    Decision::Reject(Reply::RejectOrFailed)
                 }
             };
-            debug!(peer_addr = %peer_addr, "new client connection accepted");
 
-            let handler = Arc::clone(&self.handler);
-            let peer_addr_clone = peer_addr.clone();
+            let target = match decision {
+                Decision::Grant => target,
+                Decision::Redirect(redirect) => {
+                    debug!(redirect = %redirect, "handler redirected request");
 
-            task::spawn(async move {
-                trace!("spawning new handler task");
-                trace!("processing new TCP stream");
+                    redirect
+                }
+                Decision::Reject(reply) => {
+                    warn!(reply = ?reply, "handler rejected request");
 
-                let buffer: Vec<u8> = vec![0 as u8; 65535];
+                    if let Err(e) = handshake.reply(Response::new(reply)).await {
+                        error!(error = ?e, "error writing rejection response to stream");
+                    }
 
-                let mut connection = Connection::new(stream);
+                    return;
+                }
+            };
 
-                // Request phase
-                let request = match connection.read_request::<Request>(buffer).await {
-                    Ok(r) => {
-                        trace!(?r, "received request from client");
-                        r
-                    },
-                    Err(e) => {
-                        error!(error = ?e, "failed to read request from client");
-                        return;
-                    }
-                };
-
-                debug!(?request, "received request from client");
-
-                let ip = request.get_addr();
-                let port = request.get_port();
-                let command = request.get_command();
-                let target_addr: SocketAddr = SocketAddr::new(ip, port);
-
-                trace!(command = ?command, "processing request");
-
-                async {
-                    match command {
-                        Command::Connect => {
-                            trace!("establishing connection to target");
-                            // TODO: Add timeout for connection.
-                            let target = match TcpStream::connect(target_addr).await {
-                                Ok(t) => {
-                                    trace!("successfully connected to target");
-                                    t
-                                },
-                                Err(e) => {
-                                    error!(error = %e, "failed to connect to target");
-
-                                    if let Err(e) = connection
-                                        .write_response(Response::new( Reply::RejectOrFailed))
-                                        .await
-                                    {
-                                        error!(error = ?e, "error writing connection failure response to stream");
-                                    }
-
-                                    warn!("connection to target failed, sent failure response");
-                                    return;
-                                }
-                            };
-
-                            trace!("processing request through handler");
-                            let reply = match handler.request(request.clone()) {
-                                Ok(r) => {
-                                    trace!(reply = ?r, "handler approved request");
-                                    r
-                                },
-                                Err(e) => {
-                                    error!(error = ?e, "handler rejected request");
-
-                                    if let Err(e) = connection
-                                        .write_response(Response::new(Reply::RejectOrFailed))
-                                        .await
-                                    {
-                                        error!(error = ?e, "error writing connection failure response to stream");
-                                    }
-
-                                    return;
-                                }
-                            };
-
-                            let response = Response::new(reply.clone());
-
-                            if let Err(e) = connection.write_response(response).await {
-                                error!(error = ?e, "error writing success response");
-                                return;
-                            }
-
-                            trace!("starting data relay between client and target");
-
-                            let stats = relay::relay_data(connection.into(), target).await;
-
-                            debug!(
-                                stats.bytes_to_client,
-                                stats.bytes_to_target,
-                                stats.packets_to_client,
-                                stats.packets_to_target,
-                                "relay completed"
-                            );
-                        }
-                        _ => {
-                            trace!(?command, "unsupported command");
-                        }
-                    }
-                }.instrument(span!(Level::INFO, "target", command = ?command)).await;
+            match command {
+                Command::Connect => connect(dialer, &config, &context, handshake, target).await,
+                _ => bind(handler, &config, &context, handshake, request, target).await,
+            }
+        }
+        .instrument(span)
+        .await;
 
-                trace!("handler completed");
-            }.instrument(span!(Level::INFO,"socks4", peer_addr = %peer_addr_clone)));
+        trace!("handler completed");
+    }
+    .instrument(span!(Level::INFO, "socks4", id, peer = %peer))
+    .await
+}
+
+/// Client connection on the handshake, whose request is parsed and answered by the
+/// [`state::Server`] machine, which keeps the bytes the client sends after it.
+struct Handshake {
+    connection: Connection,
+    server: state::Server,
+}
+
+impl Handshake {
+    fn new(connection: Connection) -> Self {
+        Handshake {
+            connection,
+            server: state::Server::new(),
+        }
+    }
+
+    /// Reads from the client until the machine returns the request.
+    async fn read_request(&mut self) -> Result<Request, crate::Error> {
+        loop {
+            if let Some(ServerEvent::Request(request)) = self.server.poll_event()? {
+                return Ok(request);
+            }
+
+            let bytes = self.connection.read().await?;
+            self.server.feed(&bytes);
         }
     }
+
+    async fn reply(&mut self, response: Response) -> Result<(), Error> {
+        self.server.reply(response)?;
+
+        let output = self.server.take_output();
+        self.connection.write_response(output).await
+    }
+
+    /// Answers a request the machine could not parse, which closed the handshake.
+    async fn refuse(&mut self, response: Response) -> Result<(), Error> {
+        self.connection.write_response(response).await
+    }
+
+    async fn closed(&mut self) -> Error {
+        self.connection.closed().await
+    }
+
+    /// Returns the connection once the handshake is established, with the bytes the client sent
+    /// after it in front of the ones kept from the stream.
+    fn into_connection(self) -> Connection {
+        let mut connection = self.connection;
+        connection.unread(self.server.into_remaining());
+
+        connection
+    }
+}
+
+/// Handles the CONNECT command, relaying data between the client and the target.
+async fn connect(
+    dialer: Arc<dyn Dialer>,
+    config: &Config,
+    context: &Context,
+    mut handshake: Handshake,
+    target: Target,
+) {
+    trace!("establishing connection to target");
+    let dial = dialer.dial_with_addr(context, &target);
+    let dialed = match common::timeout(config.connect_timeout, dial, "connection to target").await {
+        Ok(t) => {
+            trace!("successfully connected to target");
+            t
+        }
+        Err(e) => {
+            error!(error = %e, "failed to connect to target");
+
+            if let Err(e) = handshake.reply(Response::new(Reply::from(&e))).await {
+                error!(error = ?e, "error writing connection failure response to stream");
+            }
+
+            warn!("connection to target failed, sent failure response");
+            return;
+        }
+    };
+    let (stream, target_addr) = dialed;
+
+    let response = Response::new(Reply::Granted);
+
+    if let Err(e) = handshake.reply(response).await {
+        error!(error = ?e, "error writing success response");
+        return;
+    }
+
+    trace!("starting data relay between client and target");
+
+    let mut stats = relay::relay_connection(handshake.into_connection(), stream, config).await;
+    stats.target = Some(target);
+    stats.target_addr = target_addr;
+
+    debug!(
+        target = ?stats.target,
+        target_addr = ?stats.target_addr,
+        stats.bytes_to_client,
+        stats.bytes_to_target,
+        stats.packets_to_client,
+        stats.packets_to_target,
+        "relay completed"
+    );
+}
+
+/// Handles the BIND command.
+///
+/// A listener is opened on the address the client is connected to, and its address is sent in
+/// the first reply. Once a peer connects to it, a second reply carrying the peer address is sent
+/// and the data is relayed between the client and that peer.
+async fn bind(
+    handler: Arc<dyn Handler>,
+    config: &Config,
+    context: &Context,
+    mut handshake: Handshake,
+    request: Request,
+    target: Target,
+) {
+    // NOTE: The SOCKS4 response carries only IPv4 addresses, so the listener must be opened on an
+    // IPv4 address.
+    let local_ip = match context.local_addr.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+    };
+
+    let listener = match local_ip {
+        Some(ip) => TcpListener::bind(SocketAddrV4::new(ip, 0)).await,
+        None => Err(Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            "no IPv4 address to bind to",
+        )),
+    };
+
+    let (listener, bound_addr) = match listener.and_then(|l| {
+        let addr = l.local_addr()?;
+
+        Ok((l, addr))
+    }) {
+        Ok((l, SocketAddr::V4(addr))) => (l, addr),
+        Ok((_, addr)) => {
+            error!(bound_addr = %addr, "listener for bind request is not on an IPv4 address");
+
+            if let Err(e) = handshake.reply(Response::new(Reply::RejectOrFailed)).await {
+                error!(error = ?e, "error writing bind failure response to stream");
+            }
+
+            return;
+        }
+        Err(e) => {
+            error!(error = %e, "failed to open listener for bind request");
+
+            if let Err(e) = handshake.reply(Response::new(Reply::RejectOrFailed)).await {
+                error!(error = ?e, "error writing bind failure response to stream");
+            }
+
+            return;
+        }
+    };
+
+    debug!(bound_addr = %bound_addr, expected_addr = %target, "listening for incoming connection");
+
+    // NOTE: The first reply is sent when the server has bound a new socket, telling the client
+    // where the application server should connect to.
+    if let Err(e) = handshake
+        .reply(Response::with_addr(Reply::Granted, bound_addr))
+        .await
+    {
+        error!(error = ?e, "error writing first bind response to stream");
+
+        return;
+    }
+
+    // NOTE: The client is not expected to send anything until the second reply, so a read on its
+    // connection ends only when it goes away, and the port is not kept open for it.
+    let accepted = select! {
+        accepted = common::timeout(config.bind_timeout, listener.accept(), "incoming connection") => accepted,
+        e = handshake.closed() => {
+            debug!(error = %e, "client closed connection while waiting for incoming connection");
+
+            return;
+        }
+    };
+
+    let (peer, peer_addr) = match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            error!(error = %e, "failed to accept incoming connection");
+
+            if let Err(e) = handshake.reply(Response::new(Reply::RejectOrFailed)).await {
+                error!(error = ?e, "error writing bind failure response to stream");
+            }
+
+            return;
+        }
+    };
+
+    // NOTE: Only one incoming connection is accepted for each BIND request.
+    drop(listener);
+
+    debug!(incoming_addr = %peer_addr, "incoming connection accepted");
+
+    trace!("processing incoming connection through handler");
+    let reply = match handler.bind(context, request.clone(), peer_addr).await {
+        Ok(r) => {
+            trace!(reply = ?r, "handler approved incoming connection");
+            r
+        }
+        Err(e) => {
+            error!(error = ?e, "handler rejected incoming connection");
+
+            Reply::RejectOrFailed
+        }
+    };
+
+    let granted = matches!(reply, Reply::Granted);
+
+    let response = match peer_addr {
+        SocketAddr::V4(addr) => Response::with_addr(reply, addr),
+        SocketAddr::V6(_) => Response::new(reply),
+    };
+
+    // NOTE: The second reply is sent when the anticipated connection from the application server
+    // is established, or rejected.
+    if let Err(e) = handshake.reply(response).await {
+        error!(error = ?e, "error writing second bind response to stream");
+
+        return;
+    }
+
+    if !granted {
+        warn!(incoming_addr = %peer_addr, "incoming connection rejected, closing connection");
+
+        return;
+    }
+
+    trace!("starting data relay between client and incoming connection");
+
+    let mut stats = relay::relay_connection(handshake.into_connection(), peer, config).await;
+    stats.target = Some(target);
+    stats.target_addr = Some(peer_addr);
+
+    debug!(
+        target = ?stats.target,
+        target_addr = ?stats.target_addr,
+        stats.bytes_to_client,
+        stats.bytes_to_target,
+        stats.packets_to_client,
+        stats.packets_to_target,
+        "relay completed"
+    );
 }
@@ -0,0 +1,523 @@
+//! Sans-IO state machines of the SOCKS4 and SOCKS4a handshake.
+//!
+//! The machines do no I/O: the bytes received are fed to them, and they return the events to act
+//! on and the bytes to send, so the protocol can be driven by any event loop.
+//!
+//! As with the [SOCKS5 machines](crate::v5::state), the servers of the crate run on them, feeding
+//! them the bytes read from a [`Connection`](crate::common::Connection).
+
+use std::mem;
+
+use crate::{
+    common::take_frame,
+    v4::{client::Request, server::Response, Reply},
+    Command,
+};
+
+/// State of the server side of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerState {
+    /// Waiting for the request.
+    Request,
+    /// Waiting for [`Server::reply`].
+    Replying,
+    /// Waiting for [`Server::reply`] with the second reply of a BIND request.
+    BindReplying,
+    /// Handshake completed; the data is relayed from now on.
+    Established,
+    /// Handshake failed or rejected; the connection must be closed.
+    Closed,
+}
+
+/// Event of the server side of the handshake.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// The client sent its request, to be answered with [`Server::reply`].
+    Request(Request),
+}
+
+/// Server side of the SOCKS4 handshake.
+///
+/// # Example
+///
+/// ```rust
+/// use socks::v4::{
+///     server::Response,
+///     state::{Server, ServerEvent, ServerState},
+///     Reply,
+/// };
+///
+/// let mut server = Server::new();
+///
+/// // SOCKS4a request, sent in two segments.
+/// server.feed(&[0x04, 0x01, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01]);
+/// assert!(server.poll_event().unwrap().is_none());
+///
+/// server.feed(b"user\0example.com\0");
+/// assert!(matches!(server.poll_event(), Ok(Some(ServerEvent::Request(_)))));
+///
+/// server.reply(Response::new(Reply::Granted)).unwrap();
+/// assert_eq!(server.take_output().len(), 8);
+/// assert_eq!(server.state(), ServerState::Established);
+/// ```
+#[derive(Debug)]
+pub struct Server {
+    state: ServerState,
+    command: Command,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server {
+            state: ServerState::Request,
+            command: Command::Invalid,
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> ServerState {
+        self.state
+    }
+
+    /// Feeds bytes received from the client.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+    }
+
+    /// Returns the next event, or `None` when more bytes are needed or an answer to the last
+    /// event is awaited.
+    ///
+    /// Bytes that are not a valid packet close the handshake.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use socks::{
+    ///     v4::state::{Server, ServerState},
+    ///     Error,
+    /// };
+    ///
+    /// let mut server = Server::new();
+    ///
+    /// // SOCKS5 greeting sent to a SOCKS4 server.
+    /// server.feed(&[0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    ///
+    /// assert!(matches!(server.poll_event(), Err(Error::UnsupportedVersion(0x05))));
+    /// assert_eq!(server.state(), ServerState::Closed);
+    /// ```
+    pub fn poll_event(&mut self) -> Result<Option<ServerEvent>, crate::Error> {
+        if self.state != ServerState::Request {
+            return Ok(None);
+        }
+
+        match take_frame::<Request>(&mut self.input) {
+            Ok(Some(request)) => {
+                self.command = request.get_command();
+                self.state = ServerState::Replying;
+
+                Ok(Some(ServerEvent::Request(request)))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.state = ServerState::Closed;
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Answers the request; a granted BIND request is answered twice.
+    ///
+    /// Fails with [`crate::Error::InvalidState`] when no request is awaiting an answer.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use socks::{
+    ///     v4::{
+    ///         server::Response,
+    ///         state::{Server, ServerEvent, ServerState},
+    ///         Reply,
+    ///     },
+    ///     Error,
+    /// };
+    ///
+    /// let mut server = Server::new();
+    ///
+    /// // The request has not been received yet.
+    /// assert!(matches!(server.reply(Response::new(Reply::Granted)), Err(Error::InvalidState(_))));
+    ///
+    /// server.feed(&[0x04, 0x02, 0x00, 0x50, 10, 0, 0, 2, 0x00]);
+    /// assert!(matches!(server.poll_event(), Ok(Some(ServerEvent::Request(_)))));
+    ///
+    /// server.reply(Response::new(Reply::Granted)).unwrap();
+    /// assert_eq!(server.state(), ServerState::BindReplying);
+    ///
+    /// server.reply(Response::new(Reply::Granted)).unwrap();
+    /// assert_eq!(server.state(), ServerState::Established);
+    /// assert_eq!(server.take_output().len(), 16);
+    ///
+    /// assert!(matches!(server.reply(Response::new(Reply::Granted)), Err(Error::InvalidState(_))));
+    /// ```
+    pub fn reply(&mut self, response: Response) -> Result<(), crate::Error> {
+        let first = match self.state {
+            ServerState::Replying => true,
+            ServerState::BindReplying => false,
+            _ => return Err(crate::Error::InvalidState("reply")),
+        };
+
+        let granted = response.reply == u8::from(Reply::Granted);
+        self.output.extend(Vec::<u8>::from(response));
+
+        self.state = match (granted, &self.command) {
+            (false, _) => ServerState::Closed,
+            (true, Command::Bind) if first => ServerState::BindReplying,
+            (true, _) => ServerState::Established,
+        };
+
+        Ok(())
+    }
+
+    /// Returns the bytes to send to the client, emptying the output.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.output)
+    }
+
+    /// Returns the bytes received after the handshake, which must be relayed before anything else
+    /// read from the client.
+    pub fn into_remaining(self) -> Vec<u8> {
+        self.input
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+/// State of the client side of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    /// Waiting for the reply.
+    Reply,
+    /// Waiting for the second reply of a BIND request.
+    BindReply,
+    /// Handshake completed; the data is relayed from now on.
+    Established,
+    /// Handshake failed or rejected; the connection must be closed.
+    Closed,
+}
+
+/// Event of the client side of the handshake.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The server replied to the request.
+    Reply(Response),
+}
+
+/// Client side of the SOCKS4 handshake.
+///
+/// The request is in the output from its creation.
+///
+/// # Example
+///
+/// ```rust
+/// use socks::{
+///     common::Target,
+///     v4::{
+///         client::Request,
+///         state::{Client, ClientEvent, ClientState},
+///     },
+///     Command,
+/// };
+///
+/// let request = Request::new(Command::Connect, &Target::from(("example.com", 80)), "user").unwrap();
+/// let mut client = Client::new(request);
+///
+/// assert_eq!(client.take_output()[..8], [0x04, 0x01, 0x00, 0x50, 0x00, 0x00, 0x00, 0x01]);
+///
+/// client.feed(&[0x00, 0x5A, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+/// assert!(matches!(client.poll_event(), Ok(Some(ClientEvent::Reply(_)))));
+/// assert_eq!(client.state(), ClientState::Established);
+/// ```
+#[derive(Debug)]
+pub struct Client {
+    state: ClientState,
+    command: Command,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Client {
+    pub fn new(request: Request) -> Self {
+        Client {
+            state: ClientState::Reply,
+            command: request.get_command(),
+            input: Vec::new(),
+            output: Vec::<u8>::from(request),
+        }
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    /// Feeds bytes received from the server.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+    }
+
+    /// Returns the next event, or `None` when more bytes are needed.
+    ///
+    /// A request that is not granted is returned as a reply, and closes the handshake.
+    pub fn poll_event(&mut self) -> Result<Option<ClientEvent>, crate::Error> {
+        if !matches!(self.state, ClientState::Reply | ClientState::BindReply) {
+            return Ok(None);
+        }
+
+        let response: Response = match take_frame(&mut self.input) {
+            Ok(Some(response)) => response,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                self.state = ClientState::Closed;
+
+                return Err(e);
+            }
+        };
+
+        // NOTE: The version of the reply is 0, and not the version of the protocol.
+        if response.version != 0x00 {
+            self.state = ClientState::Closed;
+
+            return Err(crate::Error::UnsupportedVersion(response.version));
+        }
+
+        let granted = response.reply == u8::from(Reply::Granted);
+
+        self.state = match (granted, &self.command, self.state) {
+            (false, _, _) => ClientState::Closed,
+            (true, Command::Bind, ClientState::Reply) => ClientState::BindReply,
+            (true, _, _) => ClientState::Established,
+        };
+
+        Ok(Some(ClientEvent::Reply(response)))
+    }
+
+    /// Returns the bytes to send to the server, emptying the output.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.output)
+    }
+
+    /// Returns the bytes received after the handshake, which come from the target.
+    pub fn into_remaining(self) -> Vec<u8> {
+        self.input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{Client, ClientEvent, ClientState, Server, ServerEvent, ServerState};
+    use crate::{
+        common::Target,
+        v4::{client::Request, server::Response, Reply},
+        Command,
+    };
+
+    const CONNECT: [u8; 13] = [
+        0x04, 0x01, 0x00, 0x50, 127, 0, 0, 1, b'u', b's', b'e', b'r', 0x00,
+    ];
+    const BIND: [u8; 9] = [0x04, 0x02, 0x00, 0x50, 10, 0, 0, 2, 0x00];
+    const GRANTED: [u8; 8] = [0x00, 0x5A, 0x00, 0x50, 10, 0, 0, 1];
+    const REJECTED: [u8; 8] = [0x00, 0x5B, 0x00, 0x00, 0, 0, 0, 0];
+
+    #[test]
+    fn server_reads_a_request_fed_byte_by_byte() {
+        let mut server = Server::new();
+
+        for byte in &CONNECT[..CONNECT.len() - 1] {
+            server.feed(&[*byte]);
+            assert!(server.poll_event().unwrap().is_none());
+            assert_eq!(server.state(), ServerState::Request);
+        }
+
+        server.feed(&CONNECT[CONNECT.len() - 1..]);
+        let Some(ServerEvent::Request(request)) = server.poll_event().unwrap() else {
+            panic!("expected a request");
+        };
+
+        assert_eq!(request.id, "user");
+        assert_eq!(request.get_port(), 80);
+        assert_eq!(server.state(), ServerState::Replying);
+    }
+
+    #[test]
+    fn server_reads_a_socks4a_request() {
+        let mut server = Server::new();
+
+        server.feed(&[0x04, 0x01, 0x00, 0x50, 0, 0, 0, 1, 0x00]);
+        server.feed(b"example.com\0");
+
+        let Some(ServerEvent::Request(request)) = server.poll_event().unwrap() else {
+            panic!("expected a request");
+        };
+
+        assert_eq!(request.get_domain(), Some("example.com"));
+    }
+
+    #[test]
+    fn server_rejects_invalid_requests() {
+        let cases: [(&[u8], crate::Error); 2] = [
+            (
+                &[0x05, 0x01, 0x00, 0x50, 127, 0, 0, 1, 0x00],
+                crate::Error::UnsupportedVersion(0x05),
+            ),
+            (
+                &[0x04, 0x03, 0x00, 0x50, 127, 0, 0, 1, 0x00],
+                crate::Error::UnsupportedCommand(0x03),
+            ),
+        ];
+
+        for (bytes, expected) in cases {
+            let mut server = Server::new();
+            server.feed(bytes);
+
+            let error = server.poll_event().unwrap_err();
+            assert_eq!(error.to_string(), expected.to_string());
+            assert_eq!(server.state(), ServerState::Closed);
+            assert!(server.reply(Response::new(Reply::RejectOrFailed)).is_err());
+        }
+    }
+
+    #[test]
+    fn server_rejects_requests_larger_than_a_frame() {
+        let mut server = Server::new();
+
+        server.feed(&[0x04, 0x01, 0x00, 0x50, 127, 0, 0, 1]);
+        server.feed(&[b'a'; crate::common::MAX_FRAME_SIZE]);
+
+        assert!(matches!(
+            server.poll_event(),
+            Err(crate::Error::Malformed(_))
+        ));
+        assert_eq!(server.state(), ServerState::Closed);
+    }
+
+    #[test]
+    fn server_replies_and_keeps_the_bytes_after_the_request() {
+        let mut server = Server::new();
+
+        server.feed(&[&CONNECT[..], b"data"].concat());
+        assert!(server.poll_event().unwrap().is_some());
+
+        // NOTE: The bytes after the request are not parsed as another one.
+        assert!(server.poll_event().unwrap().is_none());
+
+        server.reply(Response::new(Reply::Granted)).unwrap();
+        assert_eq!(server.state(), ServerState::Established);
+        assert_eq!(server.take_output(), vec![0x00, 0x5A, 0, 0, 0, 0, 0, 0]);
+        assert!(server.reply(Response::new(Reply::Granted)).is_err());
+
+        assert_eq!(server.into_remaining(), b"data");
+    }
+
+    #[test]
+    fn server_closes_on_a_rejected_request() {
+        let mut server = Server::new();
+
+        server.feed(&BIND);
+        assert!(server.poll_event().unwrap().is_some());
+
+        server.reply(Response::new(Reply::RejectOrFailed)).unwrap();
+        assert_eq!(server.state(), ServerState::Closed);
+        assert_eq!(server.take_output()[1], 0x5B);
+    }
+
+    #[test]
+    fn server_answers_bind_requests_twice() {
+        let mut server = Server::new();
+
+        server.feed(&BIND);
+        assert!(server.poll_event().unwrap().is_some());
+
+        server.reply(Response::new(Reply::Granted)).unwrap();
+        assert_eq!(server.state(), ServerState::BindReplying);
+        assert!(server.poll_event().unwrap().is_none());
+
+        server.reply(Response::new(Reply::RejectOrFailed)).unwrap();
+        assert_eq!(server.state(), ServerState::Closed);
+        assert_eq!(server.take_output().len(), 16);
+    }
+
+    fn client(command: Command) -> Client {
+        let request =
+            Request::new(command, &Target::from((Ipv4Addr::new(10, 0, 0, 2), 80)), "").unwrap();
+
+        let mut client = Client::new(request);
+        client.take_output();
+
+        client
+    }
+
+    #[test]
+    fn client_reads_a_reply_fed_byte_by_byte() {
+        let mut client = client(Command::Connect);
+
+        for byte in &GRANTED[..GRANTED.len() - 1] {
+            client.feed(&[*byte]);
+            assert!(client.poll_event().unwrap().is_none());
+        }
+
+        client.feed(&GRANTED[GRANTED.len() - 1..]);
+        client.feed(b"data");
+
+        let Some(ClientEvent::Reply(response)) = client.poll_event().unwrap() else {
+            panic!("expected a reply");
+        };
+
+        assert_eq!(response.reply, 0x5A);
+        assert_eq!(client.state(), ClientState::Established);
+        assert_eq!(client.into_remaining(), b"data");
+    }
+
+    #[test]
+    fn client_reads_both_bind_replies() {
+        let mut client = client(Command::Bind);
+
+        client.feed(&[GRANTED, GRANTED].concat());
+
+        assert!(client.poll_event().unwrap().is_some());
+        assert_eq!(client.state(), ClientState::BindReply);
+        assert!(client.poll_event().unwrap().is_some());
+        assert_eq!(client.state(), ClientState::Established);
+    }
+
+    #[test]
+    fn client_closes_on_a_rejected_request() {
+        let mut client = client(Command::Connect);
+
+        client.feed(&REJECTED);
+
+        assert!(client.poll_event().unwrap().is_some());
+        assert_eq!(client.state(), ClientState::Closed);
+        assert!(client.poll_event().unwrap().is_none());
+    }
+
+    #[test]
+    fn client_rejects_a_reply_of_another_version() {
+        let mut client = client(Command::Connect);
+
+        client.feed(&[0x04, 0x5A, 0x00, 0x50, 10, 0, 0, 1]);
+
+        assert!(matches!(
+            client.poll_event(),
+            Err(crate::Error::UnsupportedVersion(0x04))
+        ));
+        assert_eq!(client.state(), ClientState::Closed);
+    }
+}
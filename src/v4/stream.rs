@@ -0,0 +1,182 @@
+//! SOCKS4 client stream, connected to a target through a SOCKS4 proxy server.
+
+use std::{
+    io::{Error, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, ToSocketAddrs},
+};
+use tracing::debug;
+
+use crate::{common::Target, Command, ReplyError};
+
+use super::{
+    client::Request,
+    server::SOCKS4_RESPONSE_SIZE,
+    state::{Client, ClientEvent},
+    Reply,
+};
+
+/// Stream connected to a target through a SOCKS4 proxy server.
+///
+/// Targets given as domain names are sent to the proxy to be resolved, as defined by SOCKS4a.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use socks::v4::stream::Socks4Stream;
+/// use tokio::io::AsyncWriteExt;
+///
+/// # async fn run() -> Result<(), std::io::Error> {
+/// let mut stream = Socks4Stream::connect("127.0.0.1:1080", ("example.com", 80), "user").await?;
+/// stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Socks4Stream<S = TcpStream> {
+    stream: S,
+    /// Bytes from the target received along with the reply, returned before reading the stream.
+    pending: Vec<u8>,
+}
+
+impl Socks4Stream<TcpStream> {
+    /// Connects to the target through the proxy, identifying with the user ID.
+    pub async fn connect(
+        proxy: impl ToSocketAddrs,
+        target: impl Into<Target>,
+        id: &str,
+    ) -> Result<Self, Error> {
+        let stream = TcpStream::connect(proxy).await?;
+
+        Socks4Stream::connect_with(stream, target, id).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socks4Stream<S> {
+    /// Connects to the target through a stream already connected to the proxy.
+    pub async fn connect_with(
+        mut stream: S,
+        target: impl Into<Target>,
+        id: &str,
+    ) -> Result<Self, Error> {
+        let target = target.into();
+        debug!(target = %target, "connecting to target through proxy");
+
+        let request = match Request::new(Command::Connect, &target, id) {
+            Some(r) => r,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("target {} is not supported by SOCKS4", target),
+                ))
+            }
+        };
+
+        let mut client = Client::new(request);
+
+        let response = loop {
+            if let Some(ClientEvent::Reply(response)) = client.poll_event()? {
+                break response;
+            }
+
+            let output = client.take_output();
+            if !output.is_empty() {
+                stream.write_all(&output).await?;
+            }
+
+            let mut buffer = [0u8; SOCKS4_RESPONSE_SIZE];
+            let size = stream.read(&mut buffer).await?;
+            if size == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "proxy closed connection",
+                ));
+            }
+
+            client.feed(&buffer[..size]);
+        };
+
+        let reply = Reply::try_from(response.reply)?;
+
+        if reply != Reply::Granted {
+            return Err(reply_error(reply));
+        }
+
+        debug!("connected to target through proxy");
+
+        Ok(Socks4Stream {
+            stream,
+            pending: client.into_remaining(),
+        })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Returns the stream to the proxy, discarding any bytes from the target received along with
+    /// the reply and not read yet.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Converts a reply that is not granted into an error.
+fn reply_error(reply: Reply) -> Error {
+    let kind = match reply {
+        Reply::RejectOrFailed => ErrorKind::ConnectionRefused,
+        // NOTE: The identd of the client could not confirm its user ID, so the proxy refused to
+        // serve it rather than the target refusing the connection.
+        Reply::FailedClientNotRunning | Reply::FailedClientNotConfirmed => {
+            ErrorKind::PermissionDenied
+        }
+        Reply::Granted => ErrorKind::Other,
+    };
+
+    Error::new(kind, ReplyError::V4(reply))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Socks4Stream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        if !self.pending.is_empty() {
+            let size = self.pending.len().min(buf.remaining());
+            buf.put_slice(&self.pending[..size]);
+            self.pending.drain(..size);
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Socks4Stream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
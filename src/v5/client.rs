@@ -1,6 +1,9 @@
-use std::net::{IpAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-use crate::{Command, Version};
+use crate::{
+    common::{Destination, Frame, Target},
+    Command, Version,
+};
 
 /// SOCKS5 request packet.
 #[derive(Debug, Clone)]
@@ -18,121 +21,128 @@ pub struct Request {
 }
 
 impl Request {
-    pub fn get_version(&self) -> Version {
-        return Version::from(self.version);
+    pub fn new(command: Command, addr: Address, port: u16) -> Self {
+        Request {
+            version: 0x05,
+            command: command as u8,
+            rsv: 0x00,
+            addr: addr.into(),
+            port: port.to_be_bytes(),
+        }
     }
 
-    pub fn get_command(&self) -> Command {
-        return Command::from(self.command);
+    pub fn get_version(&self) -> Version {
+        Version::from(self.version)
     }
 
-    fn resolve_hostname_to_ip(&self, hostname: &str) -> Option<IpAddr> {
-        if let Ok(socket_addrs) = (hostname, 0).to_socket_addrs() {
-            for socket_addr in socket_addrs {
-                return Some(socket_addr.ip());
-            }
-        }
-        None
+    pub fn get_command(&self) -> Command {
+        Command::from(self.command)
     }
 
+    /// Returns the IP address of the target, or `None` when it is a domain name.
     pub fn get_addr(&self) -> Option<IpAddr> {
-        let addr = Address::from(self.addr.clone());
-        match Kind::from(addr.kind) {
-            Kind::Ipv4 => {
-                if addr.address.len() != 4 {
-                    return None;
-                }
-
-                // TODO: There is a simple of doing this.
-                return Some(IpAddr::from([
-                    addr.address[0],
-                    addr.address[1],
-                    addr.address[2],
-                    addr.address[3],
-                ]));
-            }
-            Kind::Ipv6 => {
-                if addr.address.len() != 16 {
-                    return None;
-                }
-
-                // TODO: There is a simple of doing this.
-                return Some(IpAddr::from([
-                    addr.address[0],
-                    addr.address[1],
-                    addr.address[2],
-                    addr.address[3],
-                    addr.address[4],
-                    addr.address[5],
-                    addr.address[6],
-                    addr.address[7],
-                    addr.address[8],
-                    addr.address[9],
-                    addr.address[10],
-                    addr.address[11],
-                    addr.address[12],
-                    addr.address[13],
-                    addr.address[14],
-                    addr.address[15],
-                ]));
-            }
-            Kind::DomainName => {
-                if addr.address.is_empty() {
-                    return None;
-                }
-
-                if addr.address.len() < 2 {
-                    return None;
-                }
+        Address::try_from(self.addr.clone()).ok()?.get_addr()
+    }
 
-                let size = addr.address[0] as usize;
-                let address = addr.address[1..size + 1].to_vec();
+    /// Returns the requested host, without resolving its domain name.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use socks::{
+    ///     common::Destination,
+    ///     v5::client::{Address, Request},
+    ///     Command,
+    /// };
+    ///
+    /// let address = Address::try_from(&("example.com", 443).into()).unwrap();
+    /// let request = Request::new(Command::Connect, address, 443);
+    ///
+    /// assert_eq!(
+    ///     request.get_destination(),
+    ///     Some(Destination::Domain("example.com".to_string()))
+    /// );
+    /// ```
+    pub fn get_destination(&self) -> Option<Destination> {
+        Address::try_from(self.addr.clone()).ok()?.get_destination()
+    }
 
-                return self.resolve_hostname_to_ip(&String::from_utf8_lossy(&address));
-            }
-            _ => {
-                dbg!(addr);
-                todo!();
-            }
-        }
+    /// Returns the requested target, without resolving its domain name.
+    pub fn get_target(&self) -> Option<Target> {
+        Address::try_from(self.addr.clone())
+            .ok()?
+            .get_target(self.get_port())
     }
 
     pub fn get_port(&self) -> u16 {
-        return u16::from_be_bytes(self.port);
+        u16::from_be_bytes(self.port)
     }
 }
 
-impl From<&[u8]> for Request {
-    fn from(buffer: &[u8]) -> Self {
-        let addr_length = match Kind::from(buffer[3]) {
-            Kind::Ipv4 => 4,
-            Kind::DomainName => {
-                // The length of the domain name is the first byte after the address type byte.
-                // So we need to read that byte to determine the length of the domain name.
-                let domain_length = buffer[4] as usize;
-                domain_length + 1 // +1 for the address type byte
-            }
-            Kind::Ipv6 => 16,
-            Kind::Unknown => todo!(),
+impl Frame for Request {
+    fn frame_len(buffer: &[u8]) -> Result<Option<usize>, crate::Error> {
+        let Some(addr) = buffer.get(3..) else {
+            return Ok(None);
         };
 
-        let addr_end_position = addr_length + 3;
+        // NOTE: +2 for the port.
+        Ok(Kind::addr_len(addr)?.map(|addr_length| 4 + addr_length + 2))
+    }
+}
+
+impl TryFrom<&[u8]> for Request {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        if buffer.len() < 4 {
+            return Err(crate::Error::Malformed("request too short"));
+        }
+
+        if buffer[0] != 0x05 {
+            return Err(crate::Error::UnsupportedVersion(buffer[0]));
+        }
+
+        if !matches!(
+            Command::from(buffer[1]),
+            Command::Connect | Command::Bind | Command::Associate
+        ) {
+            return Err(crate::Error::UnsupportedCommand(buffer[1]));
+        }
+
+        let addr_length =
+            Kind::addr_len(&buffer[3..])?.ok_or(crate::Error::Malformed("request too short"))?;
+
+        let port_position = 4 + addr_length;
+        if buffer.len() < port_position + 2 {
+            return Err(crate::Error::Malformed("request too short"));
+        }
 
-        return Request {
+        Ok(Request {
             version: buffer[0],
             command: buffer[1],
             rsv: buffer[2],
-            // NOTE: addr_end_position is being summed with 1 because the address type byte is
-            // included in the address.
-            addr: Vec::from(&buffer[3..addr_end_position + 1]),
-            port: [buffer[addr_end_position + 1], buffer[addr_end_position + 2]],
-        };
+            // NOTE: The address type byte is included in the address.
+            addr: Vec::from(&buffer[3..port_position]),
+            port: [buffer[port_position], buffer[port_position + 1]],
+        })
     }
 }
 
-impl From<Vec<u8>> for Request {
-    fn from(buffer: Vec<u8>) -> Self {
-        return Request::from(&buffer[..]);
+impl TryFrom<Vec<u8>> for Request {
+    type Error = crate::Error;
+
+    fn try_from(buffer: Vec<u8>) -> Result<Self, Self::Error> {
+        Request::try_from(&buffer[..])
+    }
+}
+
+impl From<Request> for Vec<u8> {
+    fn from(request: Request) -> Self {
+        let mut buffer = vec![request.version, request.command, request.rsv];
+        buffer.extend_from_slice(&request.addr);
+        buffer.extend_from_slice(&request.port);
+
+        buffer
     }
 }
 
@@ -153,7 +163,7 @@ pub enum AuthMethod {
 
 impl From<u8> for AuthMethod {
     fn from(value: u8) -> Self {
-        return match value {
+        match value {
             0x00 => AuthMethod::NoAuthentication,
             0x01 => AuthMethod::Gssapi,
             0x02 => AuthMethod::UsernamePassword,
@@ -165,13 +175,13 @@ impl From<u8> for AuthMethod {
             0x08 => AuthMethod::MultiAuthenticationFramework,
             0x09 => AuthMethod::JsonParameterBlock,
             _ => AuthMethod::Unknown,
-        };
+        }
     }
 }
 
-impl Into<u8> for AuthMethod {
-    fn into(self) -> u8 {
-        self as u8
+impl From<AuthMethod> for u8 {
+    fn from(method: AuthMethod) -> Self {
+        method as u8
     }
 }
 
@@ -182,13 +192,141 @@ pub struct Greeting {
     pub auth: Vec<u8>,
 }
 
-impl From<&[u8]> for Greeting {
-    fn from(buffer: &[u8]) -> Self {
-        return Greeting {
+impl Greeting {
+    pub fn new(methods: Vec<AuthMethod>) -> Self {
+        Greeting {
+            version: 0x05,
+            number: methods.len() as u8,
+            auth: methods.into_iter().map(u8::from).collect(),
+        }
+    }
+}
+
+impl Frame for Greeting {
+    fn frame_len(buffer: &[u8]) -> Result<Option<usize>, crate::Error> {
+        Ok(buffer.get(1).map(|number| 2 + *number as usize))
+    }
+}
+
+impl TryFrom<&[u8]> for Greeting {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        if buffer.len() < 2 {
+            return Err(crate::Error::Malformed("greeting too short"));
+        }
+
+        if buffer[0] != 0x05 {
+            return Err(crate::Error::UnsupportedVersion(buffer[0]));
+        }
+
+        let auth = match buffer.get(2..2 + buffer[1] as usize) {
+            Some(auth) => auth,
+            None => return Err(crate::Error::Malformed("greeting too short")),
+        };
+
+        Ok(Greeting {
             version: buffer[0],
             number: buffer[1],
-            auth: Vec::from(&buffer[2..]),
+            auth: Vec::from(auth),
+        })
+    }
+}
+
+impl From<Greeting> for Vec<u8> {
+    fn from(greeting: Greeting) -> Self {
+        let mut buffer = vec![greeting.version, greeting.number];
+        buffer.extend_from_slice(&greeting.auth);
+
+        buffer
+    }
+}
+
+/// Username/password authentication request, as defined by RFC 1929.
+///
+/// <https://datatracker.ietf.org/doc/html/rfc1929>
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    /// Version of the sub-negotiation, should be 0x01.
+    pub version: u8,
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Credentials {
+            version: 0x01,
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+impl Frame for Credentials {
+    fn frame_len(buffer: &[u8]) -> Result<Option<usize>, crate::Error> {
+        let username_length = match buffer.get(1) {
+            Some(length) => *length as usize,
+            None => return Ok(None),
         };
+
+        Ok(buffer
+            .get(2 + username_length)
+            .map(|password_length| 3 + username_length + *password_length as usize))
+    }
+}
+
+impl TryFrom<&[u8]> for Credentials {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        if buffer.len() < 2 {
+            return Err(crate::Error::Malformed("credentials too short"));
+        }
+
+        if buffer[0] != 0x01 {
+            return Err(crate::Error::UnsupportedVersion(buffer[0]));
+        }
+
+        let username_end_position = 2 + buffer[1] as usize;
+        let username = buffer
+            .get(2..username_end_position)
+            .ok_or(crate::Error::Malformed("credentials too short"))?;
+
+        let password_length = *buffer
+            .get(username_end_position)
+            .ok_or(crate::Error::Malformed("credentials too short"))?
+            as usize;
+        let password_start_position = username_end_position + 1;
+        let password = buffer
+            .get(password_start_position..password_start_position + password_length)
+            .ok_or(crate::Error::Malformed("credentials too short"))?;
+
+        Ok(Credentials {
+            version: buffer[0],
+            username: String::from_utf8_lossy(username).to_string(),
+            password: String::from_utf8_lossy(password).to_string(),
+        })
+    }
+}
+
+impl TryFrom<Credentials> for Vec<u8> {
+    type Error = crate::Error;
+
+    /// Encodes the credentials, which fails when the username or the password is longer than the
+    /// 255 bytes its length can carry.
+    fn try_from(credentials: Credentials) -> Result<Self, Self::Error> {
+        let username_length = u8::try_from(credentials.username.len())
+            .map_err(|_| crate::Error::TooLong("username"))?;
+        let password_length = u8::try_from(credentials.password.len())
+            .map_err(|_| crate::Error::TooLong("password"))?;
+
+        let mut buffer = vec![credentials.version, username_length];
+        buffer.extend_from_slice(credentials.username.as_bytes());
+        buffer.push(password_length);
+        buffer.extend_from_slice(credentials.password.as_bytes());
+
+        Ok(buffer)
     }
 }
 
@@ -209,6 +347,38 @@ impl Kind {
             Kind::Unknown => "Unknown address type",
         }
     }
+
+    /// Returns the length of the address starting the buffer, without its type byte, or `None`
+    /// when the buffer is too short to tell.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use socks::v5::client::Kind;
+    ///
+    /// assert_eq!(Kind::addr_len(&[0x01, 127, 0, 0, 1]).unwrap(), Some(4));
+    /// assert_eq!(Kind::addr_len(&[0x03, 11]).unwrap(), Some(12));
+    /// assert_eq!(Kind::addr_len(&[0x03]).unwrap(), None);
+    /// assert!(Kind::addr_len(&[0x02]).is_err());
+    /// ```
+    pub fn addr_len(buffer: &[u8]) -> Result<Option<usize>, crate::Error> {
+        let Some(kind) = buffer.first() else {
+            return Ok(None);
+        };
+
+        let length = match Kind::from(*kind) {
+            Kind::Ipv4 => 4,
+            Kind::DomainName => match buffer.get(1) {
+                // NOTE: +1 for the byte holding the length of the domain name.
+                Some(length) => *length as usize + 1,
+                None => return Ok(None),
+            },
+            Kind::Ipv6 => 16,
+            Kind::Unknown => return Err(crate::Error::UnsupportedAddressType(*kind)),
+        };
+
+        Ok(Some(length))
+    }
 }
 
 impl From<u8> for Kind {
@@ -222,9 +392,9 @@ impl From<u8> for Kind {
     }
 }
 
-impl Into<u8> for Kind {
-    fn into(self) -> u8 {
-        self as u8
+impl From<Kind> for u8 {
+    fn from(kind: Kind) -> Self {
+        kind as u8
     }
 }
 
@@ -234,11 +404,102 @@ pub struct Address {
     pub address: Vec<u8>,
 }
 
-impl From<Vec<u8>> for Address {
-    fn from(buffer: Vec<u8>) -> Self {
-        return Address {
-            kind: buffer[0],
-            address: Vec::from(&buffer[1..]),
-        };
+impl Address {
+    /// Returns the address as a destination, without resolving it when it is a domain name.
+    pub fn get_destination(&self) -> Option<Destination> {
+        match Kind::from(self.kind) {
+            Kind::Ipv4 => {
+                let octets: [u8; 4] = self.address.as_slice().try_into().ok()?;
+
+                Some(Destination::Ipv4(Ipv4Addr::from(octets)))
+            }
+            Kind::Ipv6 => {
+                let octets: [u8; 16] = self.address.as_slice().try_into().ok()?;
+
+                Some(Destination::Ipv6(Ipv6Addr::from(octets)))
+            }
+            Kind::DomainName => {
+                let size = *self.address.first()? as usize;
+                let address = self.address.get(1..size + 1)?;
+
+                Some(Destination::Domain(
+                    String::from_utf8_lossy(address).to_string(),
+                ))
+            }
+            Kind::Unknown => None,
+        }
+    }
+
+    /// Returns the address as a target, without resolving it when it is a domain name.
+    pub fn get_target(&self, port: u16) -> Option<Target> {
+        Some(Target::from((self.get_destination()?, port)))
+    }
+
+    /// Returns the IP address, or `None` when it is a domain name, which is resolved by the
+    /// [`Resolver`](crate::common::Resolver) of the server.
+    pub fn get_addr(&self) -> Option<IpAddr> {
+        self.get_destination()?.ip()
+    }
+}
+
+impl TryFrom<Vec<u8>> for Address {
+    type Error = crate::Error;
+
+    /// Creates an address from its type byte followed by the address itself.
+    fn try_from(buffer: Vec<u8>) -> Result<Self, Self::Error> {
+        match buffer.split_first() {
+            Some((kind, address)) => Ok(Address {
+                kind: *kind,
+                address: address.to_vec(),
+            }),
+            None => Err(crate::Error::Malformed("empty address")),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => Address {
+                kind: Kind::Ipv4.into(),
+                address: addr.ip().octets().to_vec(),
+            },
+            SocketAddr::V6(addr) => Address {
+                kind: Kind::Ipv6.into(),
+                address: addr.ip().octets().to_vec(),
+            },
+        }
+    }
+}
+
+impl TryFrom<&Target> for Address {
+    type Error = crate::Error;
+
+    fn try_from(target: &Target) -> Result<Self, Self::Error> {
+        match target {
+            Target::Addr(addr) => Ok(Address::from(*addr)),
+            Target::Domain(domain, _) => {
+                // NOTE: The length of the domain name is carried in a single byte.
+                let size =
+                    u8::try_from(domain.len()).map_err(|_| crate::Error::TooLong("domain name"))?;
+
+                let mut address = vec![size];
+                address.extend_from_slice(domain.as_bytes());
+
+                Ok(Address {
+                    kind: Kind::DomainName.into(),
+                    address,
+                })
+            }
+        }
+    }
+}
+
+impl From<Address> for Vec<u8> {
+    fn from(address: Address) -> Self {
+        let mut buffer = vec![address.kind];
+        buffer.extend_from_slice(&address.address);
+
+        buffer
     }
 }
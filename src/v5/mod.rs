@@ -12,6 +12,13 @@
 pub mod client;
 pub mod server;
 pub mod socks;
+pub mod state;
+pub mod stream;
+pub mod udp;
+
+use std::io::{self, ErrorKind};
+
+use crate::{v4, ReplyError};
 
 /// Reply code.
 ///
@@ -35,25 +42,151 @@ pub enum Reply {
     AddressTypeNotSupported = 0x08,
 }
 
-impl From<u8> for Reply {
-    fn from(byte: u8) -> Self {
+impl TryFrom<u8> for Reply {
+    type Error = crate::Error;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
         match byte {
-            0x00 => Reply::RequestGranted,
-            0x01 => Reply::GeneralFailure,
-            0x02 => Reply::ConnectionNotAllowedByRuleset,
-            0x03 => Reply::NetworkUnreachable,
-            0x04 => Reply::HostUnreachable,
-            0x05 => Reply::ConnectionRefusedByDestinationHost,
-            0x06 => Reply::TtlExpired,
-            0x07 => Reply::CommandNotSupportedOrProtocolError,
-            0x08 => Reply::AddressTypeNotSupported,
-            _ => panic!("Unknown error code: {}", byte),
+            0x00 => Ok(Reply::RequestGranted),
+            0x01 => Ok(Reply::GeneralFailure),
+            0x02 => Ok(Reply::ConnectionNotAllowedByRuleset),
+            0x03 => Ok(Reply::NetworkUnreachable),
+            0x04 => Ok(Reply::HostUnreachable),
+            0x05 => Ok(Reply::ConnectionRefusedByDestinationHost),
+            0x06 => Ok(Reply::TtlExpired),
+            0x07 => Ok(Reply::CommandNotSupportedOrProtocolError),
+            0x08 => Ok(Reply::AddressTypeNotSupported),
+            _ => Err(crate::Error::UnknownReply(byte)),
         }
     }
 }
 
-impl Into<u8> for Reply {
-    fn into(self) -> u8 {
-        self as u8
+impl From<Reply> for u8 {
+    fn from(reply: Reply) -> Self {
+        reply as u8
+    }
+}
+
+impl From<&io::Error> for Reply {
+    /// Maps the error of a failed connection to a target onto the reply that describes it.
+    ///
+    /// The reply of an upstream SOCKS5 proxy, carried by a [`ReplyError`], is kept as it is, the
+    /// reply of an upstream SOCKS4 proxy is mapped onto the closest SOCKS5 reply, and a domain
+    /// name that could not be resolved is reported as an unreachable host.
+    fn from(error: &io::Error) -> Self {
+        if let Some(inner) = error.get_ref() {
+            match inner.downcast_ref::<ReplyError>() {
+                Some(ReplyError::V5(reply)) => return reply.clone(),
+                Some(ReplyError::V4(
+                    v4::Reply::FailedClientNotRunning | v4::Reply::FailedClientNotConfirmed,
+                )) => return Reply::ConnectionNotAllowedByRuleset,
+                Some(ReplyError::V4(_)) => return Reply::GeneralFailure,
+                None => {}
+            }
+
+            if let Some(crate::Error::Resolution(_)) = inner.downcast_ref::<crate::Error>() {
+                return Reply::HostUnreachable;
+            }
+        }
+
+        match error.kind() {
+            ErrorKind::ConnectionRefused => Reply::ConnectionRefusedByDestinationHost,
+            ErrorKind::HostUnreachable | ErrorKind::NotFound => Reply::HostUnreachable,
+            ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown => Reply::NetworkUnreachable,
+            ErrorKind::TimedOut => Reply::TtlExpired,
+            ErrorKind::PermissionDenied => Reply::ConnectionNotAllowedByRuleset,
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+
+    use super::Reply;
+    use crate::{v4, ReplyError};
+
+    #[test]
+    fn reply_from_error_kind() {
+        let cases = [
+            (
+                ErrorKind::ConnectionRefused,
+                Reply::ConnectionRefusedByDestinationHost,
+            ),
+            (ErrorKind::HostUnreachable, Reply::HostUnreachable),
+            (ErrorKind::NotFound, Reply::HostUnreachable),
+            (ErrorKind::NetworkUnreachable, Reply::NetworkUnreachable),
+            (ErrorKind::NetworkDown, Reply::NetworkUnreachable),
+            (ErrorKind::TimedOut, Reply::TtlExpired),
+            (
+                ErrorKind::PermissionDenied,
+                Reply::ConnectionNotAllowedByRuleset,
+            ),
+            (ErrorKind::ConnectionReset, Reply::GeneralFailure),
+            (ErrorKind::Other, Reply::GeneralFailure),
+        ];
+
+        for (kind, reply) in cases {
+            assert_eq!(Reply::from(&Error::from(kind)), reply, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn reply_from_error_payload() {
+        let cases = [
+            (
+                Error::other(ReplyError::V5(Reply::TtlExpired)),
+                Reply::TtlExpired,
+            ),
+            (
+                Error::new(
+                    ErrorKind::ConnectionRefused,
+                    ReplyError::V5(Reply::AddressTypeNotSupported),
+                ),
+                Reply::AddressTypeNotSupported,
+            ),
+            (
+                Error::new(
+                    ErrorKind::ConnectionRefused,
+                    ReplyError::V4(v4::Reply::RejectOrFailed),
+                ),
+                Reply::GeneralFailure,
+            ),
+            (
+                Error::new(
+                    ErrorKind::PermissionDenied,
+                    ReplyError::V4(v4::Reply::FailedClientNotRunning),
+                ),
+                Reply::ConnectionNotAllowedByRuleset,
+            ),
+            (
+                Error::new(
+                    ErrorKind::PermissionDenied,
+                    ReplyError::V4(v4::Reply::FailedClientNotConfirmed),
+                ),
+                Reply::ConnectionNotAllowedByRuleset,
+            ),
+            (
+                Error::from(crate::Error::Resolution("example.com".to_string())),
+                Reply::HostUnreachable,
+            ),
+            (
+                Error::other(crate::Error::Resolution("example.com".to_string())),
+                Reply::HostUnreachable,
+            ),
+            (
+                Error::from(crate::Error::UnknownReply(0x09)),
+                Reply::GeneralFailure,
+            ),
+            (
+                Error::new(ErrorKind::TimedOut, "timed out"),
+                Reply::TtlExpired,
+            ),
+        ];
+
+        for (error, reply) in cases {
+            assert_eq!(Reply::from(&error), reply, "{}", error);
+        }
     }
 }
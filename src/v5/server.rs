@@ -1,4 +1,11 @@
-use super::Reply;
+use std::net::SocketAddr;
+
+use crate::common::Frame;
+
+use super::{
+    client::{Address, Kind},
+    Reply,
+};
 
 /// SOCKS5 response packet.
 #[derive(Debug, Clone)]
@@ -20,23 +27,78 @@ pub struct Response {
 
 impl Response {
     pub fn new(reply: Reply, ip: Vec<u8>, port: [u8; 2]) -> Self {
-        return Response {
+        Response {
             version: 0x05,
             reply: reply.into(),
             rsv: 0x00,
             ip,
             port,
+        }
+    }
+
+    /// Creates a response carrying a socket address, as the BIND replies and the UDP ASSOCIATE
+    /// reply do.
+    pub fn with_addr(reply: Reply, addr: SocketAddr) -> Self {
+        Response::new(reply, Address::from(addr).into(), addr.port().to_be_bytes())
+    }
+
+    pub fn get_reply(&self) -> Result<Reply, crate::Error> {
+        Reply::try_from(self.reply)
+    }
+
+    pub fn get_port(&self) -> u16 {
+        u16::from_be_bytes(self.port)
+    }
+}
+
+impl Frame for Response {
+    fn frame_len(buffer: &[u8]) -> Result<Option<usize>, crate::Error> {
+        let Some(addr) = buffer.get(3..) else {
+            return Ok(None);
         };
+
+        // NOTE: +2 for the port.
+        Ok(Kind::addr_len(addr)?.map(|addr_length| 4 + addr_length + 2))
+    }
+}
+
+impl TryFrom<&[u8]> for Response {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        if buffer.len() < 4 {
+            return Err(crate::Error::Malformed("response too short"));
+        }
+
+        if buffer[0] != 0x05 {
+            return Err(crate::Error::UnsupportedVersion(buffer[0]));
+        }
+
+        let addr_length =
+            Kind::addr_len(&buffer[3..])?.ok_or(crate::Error::Malformed("response too short"))?;
+
+        let port_position = 4 + addr_length;
+        if buffer.len() < port_position + 2 {
+            return Err(crate::Error::Malformed("response too short"));
+        }
+
+        Ok(Response {
+            version: buffer[0],
+            reply: buffer[1],
+            rsv: buffer[2],
+            ip: Vec::from(&buffer[3..port_position]),
+            port: [buffer[port_position], buffer[port_position + 1]],
+        })
     }
 }
 
-impl Into<Vec<u8>> for Response {
-    fn into(self) -> Vec<u8> {
-        let mut buffer = vec![self.version, self.reply, self.rsv];
-        buffer.append(&mut self.ip.clone());
-        buffer.append(&mut self.port.clone().to_vec());
+impl From<Response> for Vec<u8> {
+    fn from(response: Response) -> Self {
+        let mut buffer = vec![response.version, response.reply, response.rsv];
+        buffer.extend_from_slice(&response.ip);
+        buffer.extend_from_slice(&response.port);
 
-        return buffer.to_vec();
+        buffer
     }
 }
 
@@ -48,30 +110,95 @@ pub struct Choice {
     pub choose: u8,
 }
 
-impl From<Vec<u8>> for Choice {
-    fn from(buffer: Vec<u8>) -> Self {
-        return Choice {
+impl From<[u8; 2]> for Choice {
+    fn from(buffer: [u8; 2]) -> Self {
+        Choice {
             version: buffer[0],
             choose: buffer[1],
-        };
+        }
+    }
+}
+
+impl Frame for Choice {
+    fn frame_len(_: &[u8]) -> Result<Option<usize>, crate::Error> {
+        Ok(Some(2))
     }
 }
 
-impl Into<[u8; 2]> for Choice {
-    fn into(self) -> [u8; 2] {
-        let mut buffer = [0 as u8; 2];
-        buffer[0] = self.version;
-        buffer[1] = self.choose;
+impl TryFrom<&[u8]> for Choice {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        let buffer: [u8; 2] = buffer
+            .try_into()
+            .map_err(|_| crate::Error::Malformed("choice must be 2 bytes"))?;
 
-        return buffer;
+        Ok(Choice::from(buffer))
+    }
+}
+
+impl From<Choice> for [u8; 2] {
+    fn from(choice: Choice) -> Self {
+        [choice.version, choice.choose]
     }
 }
 
 impl Default for Choice {
     fn default() -> Self {
-        return Choice {
+        Choice {
             version: 0x05,
             choose: 0x00,
-        };
+        }
+    }
+}
+
+/// Username/password authentication status, as defined by RFC 1929.
+#[derive(Debug, Clone)]
+pub struct Status {
+    /// Version of the sub-negotiation, should be 0x01.
+    pub version: u8,
+    /// Status, 0x00 on success; any other value is a failure, and the connection must be closed.
+    pub status: u8,
+}
+
+impl Status {
+    pub fn new(success: bool) -> Self {
+        Status {
+            version: 0x01,
+            status: if success { 0x00 } else { 0x01 },
+        }
+    }
+}
+
+impl From<[u8; 2]> for Status {
+    fn from(buffer: [u8; 2]) -> Self {
+        Status {
+            version: buffer[0],
+            status: buffer[1],
+        }
+    }
+}
+
+impl Frame for Status {
+    fn frame_len(_: &[u8]) -> Result<Option<usize>, crate::Error> {
+        Ok(Some(2))
+    }
+}
+
+impl TryFrom<&[u8]> for Status {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        let buffer: [u8; 2] = buffer
+            .try_into()
+            .map_err(|_| crate::Error::Malformed("status must be 2 bytes"))?;
+
+        Ok(Status::from(buffer))
+    }
+}
+
+impl From<Status> for [u8; 2] {
+    fn from(status: Status) -> Self {
+        [status.version, status.status]
     }
 }
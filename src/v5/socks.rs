@@ -1,222 +1,807 @@
-use std::{io::Error, net::SocketAddr, sync::Arc};
+use std::{future::Future, io::Error, net::SocketAddr, sync::Arc};
 
 use tokio::{
-    net::{TcpListener, TcpStream, ToSocketAddrs},
-    task,
+    net::{TcpListener, UdpSocket},
+    select,
 };
-use tracing::{debug, error, span, trace, warn, Instrument, Level};
+use tracing::{debug, error, field, span, trace, warn, Instrument, Level};
 
 use crate::{
-    common::{relay, Connection},
+    async_trait,
+    common::{
+        self, relay, Config, Connection, Context, Decision, Dialer, Identity, Server, Service,
+        Target,
+    },
     v5::{
-        client::{Greeting, Request},
+        client::{Credentials, Greeting, Request},
         server::Response,
+        state::{self, ServerEvent, ServerState},
         Reply,
     },
-    Command,
+    Command, Version,
 };
 
 use super::server::Choice;
 
+/// Handler of SOCKS5 clients.
+///
+/// Each method receives the [`Context`] of the client connection it is called for, and is
+/// implemented with the [`async_trait`] attribute.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::Error;
+///
+/// use socks::{
+///     async_trait,
+///     common::{Context, Decision},
+///     v5::{
+///         client::{Greeting, Request},
+///         server::Choice,
+///         socks::Handler,
+///         Reply,
+///     },
+/// };
+///
+/// struct Example;
+///
+/// #[async_trait]
+/// impl Handler for Example {
+///     async fn auth(&self, _: &Context, _: Greeting) -> Result<Choice, Error> {
+///         Ok(Choice::default())
+///     }
+///
+///     async fn request(&self, context: &Context, _: Request) -> Result<Decision<Reply>, Error> {
+///         if context.peer.addr().is_some_and(|addr| addr.ip().is_loopback()) {
+///             Ok(Decision::Grant)
+///         } else {
+///             Ok(Decision::Reject(Reply::ConnectionNotAllowedByRuleset))
+///         }
+///     }
+/// }
+/// ```
+#[async_trait]
 pub trait Handler: Send + Sync + 'static {
-    fn auth(&self, greeting: Greeting) -> Result<Choice, Error>;
-    fn request(&self, request: Request) -> Result<Reply, Error>;
+    async fn auth(&self, context: &Context, greeting: Greeting) -> Result<Choice, Error>;
+    /// Decides on a request before the server acts on it, granting it as sent, redirecting it to
+    /// another target, or rejecting it with a reply.
+    ///
+    /// The target of a UDP ASSOCIATE request is the address the client sends its datagrams from,
+    /// so the destinations of the datagrams are decided on [`Handler::datagram`] instead.
+    async fn request(&self, context: &Context, request: Request) -> Result<Decision<Reply>, Error>;
+    /// Verifies the credentials sent by the client when the username/password method, defined by
+    /// RFC 1929, is the one chosen on [`Handler::auth`].
+    ///
+    /// The default implementation rejects any credentials.
+    async fn authenticate(
+        &self,
+        context: &Context,
+        credentials: Credentials,
+    ) -> Result<bool, Error> {
+        let _ = (context, credentials);
+
+        Ok(false)
+    }
+    /// Decides whether the peer that connected to the port opened by a BIND request is accepted.
+    ///
+    /// The default implementation accepts only a peer whose IP address is the DST.ADDR of the
+    /// request, which RFC 1928 has the server use to evaluate the BIND request, or one of the
+    /// addresses the resolver of the server finds for its domain name. A request whose DST.ADDR is
+    /// unspecified accepts any peer, as the client does not know the address of the peer.
+    async fn bind(
+        &self,
+        context: &Context,
+        request: Request,
+        peer_addr: SocketAddr,
+    ) -> Result<Reply, Error> {
+        let accepted = match request.get_target() {
+            Some(Target::Addr(addr)) => addr.ip().is_unspecified() || addr.ip() == peer_addr.ip(),
+            Some(Target::Domain(domain, _)) => context
+                .resolve(&domain)
+                .await
+                .is_ok_and(|addrs| addrs.contains(&peer_addr.ip())),
+            None => false,
+        };
+
+        if accepted {
+            Ok(Reply::RequestGranted)
+        } else {
+            Ok(Reply::ConnectionNotAllowedByRuleset)
+        }
+    }
+    /// Decides whether a datagram of a UDP association is relayed to its destination, before the
+    /// destination is resolved; datagrams that are not are dropped.
+    ///
+    /// The default implementation relays every datagram.
+    async fn datagram(&self, context: &Context, target: &Target) -> Result<bool, Error> {
+        let _ = (context, target);
+
+        Ok(true)
+    }
 }
 
-pub struct Socks {
+/// SOCKS5 server, serving its clients with a [`Handler`].
+///
+/// The methods shared by every server are documented on [`Server`].
+pub type Socks = Server<Socks5>;
+
+/// Service of the SOCKS5 servers.
+pub struct Socks5 {
     handler: Arc<dyn Handler>,
 }
 
 impl Socks {
     pub fn new(internal: impl Handler) -> Self {
         debug!("initializing server with custom handler");
-        return Socks {
+
+        Server::from_service(Socks5 {
             handler: Arc::new(internal),
-        };
+        })
     }
+}
 
-    pub async fn listen(&self, addr: impl ToSocketAddrs) -> Result<(), Error> {
-        let listener = TcpListener::bind(addr).await?;
-        let local_addr = listener.local_addr()?;
-        debug!(local_addr = %local_addr, "server listening for connections");
+impl Service for Socks5 {
+    const VERSION: Version = Version::V5;
+
+    fn serve(
+        &self,
+        dialer: Arc<dyn Dialer>,
+        config: Arc<Config>,
+        connection: Connection,
+        context: Context,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        serve(
+            Arc::clone(&self.handler),
+            dialer,
+            config,
+            connection,
+            context,
+        )
+    }
+}
 
-        loop {
-            let (stream, _) = listener.accept().await?;
-            let peer_addr = match stream.peer_addr() {
-                Ok(addr) => addr,
-                Err(e) => {
-                    error!(error = %e, "failed to get peer address for incoming connection");
-                    continue;
-                }
-            };
-            debug!(peer_addr = %peer_addr, "new client connection accepted");
-
-            let handler = Arc::clone(&self.handler);
-            let peer_addr_clone = peer_addr.clone();
-
-            task::spawn(
-                async move {
-                    trace!("spawned new handler task");
-                    trace!("processing new TCP stream");
-
-                    let mut buffer: Vec<u8> = vec![0 as u8; 65535];
-
-                    // Greeting phase
-                    let mut connection = Connection::new(stream);
-
-                    trace!("reading greeting from client");
-                    let greeting = match connection.read_greeting(&mut buffer).await {
-                        Ok(g) => {
-                            trace!("received greeting from client");
-                            g
-                        }
-                        Err(e) => {
-                            error!(error = %e, "failed to read greeting from client");
-
-                            return;
-                        }
-                    };
-
-
-                    // Authentication phase
-                    debug!("processing authentication request");
-                    let choice = match handler.auth(greeting.clone()) {
-                        Ok(c) => {
-                            debug!(auth_method = ?c, "authentication successful");
-                            c
-                        }
-                        Err(e) => {
-                            error!(error = %e, "authentication failed");
-                            return;
-                        }
-                    };
-
-                    if let Err(e) = connection.write_choice(choice).await {
-                        error!(error = ?e, "error writing authentication choice to stream");
+/// Serves a single client connection, from the greeting to the end of the command.
+pub(crate) async fn serve(
+    handler: Arc<dyn Handler>,
+    dialer: Arc<dyn Dialer>,
+    config: Arc<Config>,
+    connection: Connection,
+    mut context: Context,
+) {
+    let id = context.id;
+    let peer = context.peer;
+    let mut handshake = Handshake::new(connection);
+
+    async move {
+        trace!("spawned new handler task");
+        trace!("processing new client stream");
+
+        // Greeting phase
+        trace!("reading greeting from client");
+        let greeting = match handshake.read_greeting().await {
+            Ok(g) => {
+                trace!("received greeting from client");
+                g
+            }
+            Err(e) => {
+                error!(error = %e, "failed to read greeting from client");
+
+                return;
+            }
+        };
+
+        // Authentication phase
+        debug!("processing authentication request");
+        let choice = match handler.auth(&context, greeting.clone()).await {
+            Ok(c) => {
+                debug!(auth_method = ?c, "authentication successful");
+                c
+            }
+            Err(e) => {
+                error!(error = %e, "authentication failed");
+                return;
+            }
+        };
+
+        if let Err(e) = handshake.choose(choice).await {
+            error!(error = ?e, "error writing authentication choice to stream");
+
+            return;
+        }
+
+        match handshake.state() {
+            ServerState::Credentials => {
+                trace!("reading credentials from client");
+                let credentials = match handshake.read_credentials().await {
+                    Ok(c) => {
+                        trace!(username = %c.username, "received credentials from client");
+                        c
+                    }
+                    Err(e) => {
+                        error!(error = %e, "failed to read credentials from client");
 
                         return;
                     }
+                };
 
-                    let request = match connection.read_request::<Request>(buffer).await {
-                        Ok(r) => {
-                            trace!(?r, "received request from client");
-                            r
-                        }
-                        Err(e) => {
-                            error!(error = %e, "failed to read request from client");
-                            return;
-                        }
-                    };
+                let username = credentials.username.clone();
+                let success = match handler.authenticate(&context, credentials).await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!(error = %e, "failed to verify credentials");
 
-                    debug!(?request, "received request from client");
+                        false
+                    }
+                };
 
-                    let ip = if let Some(addr) = request.get_addr() {
-                        addr
-                    } else {
-                        error!("failed to resolve address from request");
+                if let Err(e) = handshake.authenticate(success).await {
+                    error!(error = ?e, "error writing authentication status to stream");
 
-                        return;
-                    };
-
-                    let port = request.get_port();
-                    let command = request.get_command();
-                    let target_addr: SocketAddr = SocketAddr::new(ip, port);
-
-                    trace!("processing request");
-
-                    async {
-                        match command {
-                            Command::Connect => {
-                                trace!("establishing connection to target");
-                                // TODO: Add timeout for connection.
-                                let target = match TcpStream::connect(target_addr).await {
-                                    Ok(t) => {
-                                        trace!("successfully connected to target");
-                                        t
-                                    }
-                                    Err(e) => {
-                                        error!(error = %e, "failed to connect to target");
-
-                                        if let Err(e) = connection
-                                            .write_response(Response::new(
-                                                Reply::GeneralFailure,
-                                                request.addr.to_vec(),
-                                                request.port.clone(),
-                                            ))
-                                            .await
-                                        {
-                                            error!(error = ?e, "error writing connection failure response to stream");
-                                        }
-
-                                        warn!("connection to target failed, sent failure response");
-
-                                        return;
-                                    }
-                                };
-
-                                trace!("processing request through handler");
-                                let reply = match handler.request(request.clone()) {
-                                    Ok(r) => {
-                                        trace!(reply = ?r, "handler approved request");
-                                        r
-                                    }
-                                    Err(e) => {
-                                        error!(error = ?e, "handler rejected request");
-
-                                        if let Err(e) = connection
-                                            .write_response(Response::new(
-                                                Reply::GeneralFailure,
-                                                request.addr.to_vec(),
-                                                request.port.clone(),
-                                            ))
-                                            .await
-                                        {
-                                            error!(error = ?e, "error writing handler failure response");
-                                        }
-
-                                        return;
-                                    }
-                                };
-
-                                let response = Response::new(
-                                    reply.clone(),
-                                    request.addr.to_vec(),
-                                    request.port.clone(),
-                                );
-
-                                if let Err(e) = connection.write_response(response).await {
-                                    error!(error = ?e, "error writing success response to stream");
-
-                                    return;
-                                }
-
-                                trace!("starting data relay between client and target");
-
-                                let stats =
-                                    relay::relay_data(connection.into(), target).await;
-
-                                debug!(
-                                    stats.bytes_to_client,
-                                    stats.bytes_to_target,
-                                    stats.packets_to_client,
-                                    stats.packets_to_target,
-                                    "relay completed"
-                                );
-                            }
-                            _ => {
-                                trace!(?command, "command is not supported");
-                            }
-                        }
+                    return;
+                }
+
+                if !success {
+                    warn!(username = %username, "credentials rejected, closing connection");
+
+                    return;
+                }
+
+                debug!(username = %username, "credentials accepted");
+
+                context.identity = Some(Identity::Username(username));
+            }
+            ServerState::Closed => {
+                // NOTE: 0xFF means that none of the methods listed by the client are
+                // acceptable, and the client must close the connection.
+                debug!("no acceptable authentication method, closing connection");
+
+                return;
+            }
+            _ => {}
+        }
+
+        let request = match handshake.read_request().await {
+            Ok(r) => {
+                trace!(?r, "received request from client");
+                r
+            }
+            Err(crate::Error::Io(e)) => {
+                error!(error = %e, "failed to read request from client");
+                return;
+            }
+            Err(e) => {
+                error!(error = %e, "invalid request from client");
+
+                let reply = match e {
+                    crate::Error::UnsupportedAddressType(_) => Reply::AddressTypeNotSupported,
+                    _ => Reply::CommandNotSupportedOrProtocolError,
+                };
+
+                if let Err(e) = handshake
+                    .refuse(Response::with_addr(
+                        reply,
+                        SocketAddr::from(([0, 0, 0, 0], 0)),
+                    ))
+                    .await
+                {
+                    error!(error = ?e, "error writing invalid request response to stream");
+                }
+
+                return;
+            }
+        };
+
+        debug!(
+            command = ?request.get_command(),
+            destination = ?request.get_destination(),
+            port = request.get_port(),
+            "received request from client"
+        );
+
+        let command = request.get_command();
+        let target = match request.get_target() {
+            Some(t) => t,
+            None => {
+                error!("unsupported address type on request");
+
+                if let Err(e) = handshake
+                    .reply(Response::new(
+                        Reply::AddressTypeNotSupported,
+                        request.addr.to_vec(),
+                        request.port,
+                    ))
+                    .await
+                {
+                    error!(error = ?e, "error writing address type not supported response");
+                }
+
+                return;
+            }
+        };
+
+        trace!("processing request");
+
+        let span = span!(
+            Level::INFO,
+            "target",
+            target = %target,
+            command = ?command,
+            target_addr = field::Empty
+        );
+
+        async {
+            if !matches!(
+                command,
+                Command::Connect | Command::Bind | Command::Associate
+            ) {
+                trace!(?command, "command is not supported");
+
+                if let Err(e) = handshake
+                    .reply(Response::new(
+                        Reply::CommandNotSupportedOrProtocolError,
+                        request.addr.to_vec(),
+                        request.port,
+                    ))
+                    .await
+                {
+                    error!(error = ?e, "error writing command not supported response");
+                }
+
+                return;
+            }
+
+            // NOTE: The handler decides on the request before anything is done for it, so no
+            // connection is opened to a target it rejects.
+            trace!("processing request through handler");
+            let decision = match handler.request(&context, request.clone()).await {
+                Ok(d) => {
+                    trace!(decision = ?d, "handler decided on request");
+                    d
+                }
+                Err(e) => {
+                    error!(error = ?e, "handler failed to decide on request");
+
+                    Decision::Reject(Reply::GeneralFailure)
+                }
+            };
+
+            let target = match decision {
+                Decision::Grant => target,
+                Decision::Redirect(redirect) => {
+                    debug!(redirect = %redirect, "handler redirected request");
+
+                    redirect
+                }
+                Decision::Reject(reply) => {
+                    warn!(reply = ?reply, "handler rejected request");
+
+                    if let Err(e) = handshake
+                        .reply(Response::new(reply, request.addr.to_vec(), request.port))
+                        .await
+                    {
+                        error!(error = ?e, "error writing rejection response to stream");
                     }
-                    .instrument(
-                        span!(Level::INFO, "target", target_addr = %target_addr, command = ?command),
-                    )
-                    .await;
 
-                    trace!("handler completed");
+                    return;
                 }
-                .instrument(span!(Level::INFO, "socks5", peer_addr = %peer_addr_clone)),
-            );
+            };
+
+            match command {
+                Command::Connect => {
+                    connect(dialer, &config, &context, handshake, request, target).await
+                }
+                Command::Bind => bind(handler, &config, &context, handshake, request).await,
+                _ => associate(handler, &config, &context, handshake, request).await,
+            }
+        }
+        .instrument(span)
+        .await;
+
+        trace!("handler completed");
+    }
+    .instrument(span!(Level::INFO, "socks5", id, peer = %peer))
+    .await
+}
+
+/// Client connection on the handshake, whose packets are parsed and answered by the
+/// [`state::Server`] machine, which keeps the bytes the client sends after them.
+struct Handshake {
+    connection: Connection,
+    server: state::Server,
+}
+
+impl Handshake {
+    fn new(connection: Connection) -> Self {
+        Handshake {
+            connection,
+            server: state::Server::new(),
+        }
+    }
+
+    fn state(&self) -> ServerState {
+        self.server.state()
+    }
+
+    /// Reads from the client until the machine returns the next event.
+    async fn next_event(&mut self) -> Result<ServerEvent, crate::Error> {
+        loop {
+            if let Some(event) = self.server.poll_event()? {
+                return Ok(event);
+            }
+
+            let bytes = self.connection.read().await?;
+            self.server.feed(&bytes);
         }
     }
+
+    async fn read_greeting(&mut self) -> Result<Greeting, crate::Error> {
+        match self.next_event().await? {
+            ServerEvent::Greeting(greeting) => Ok(greeting),
+            _ => Err(crate::Error::InvalidState("greeting")),
+        }
+    }
+
+    async fn read_credentials(&mut self) -> Result<Credentials, crate::Error> {
+        match self.next_event().await? {
+            ServerEvent::Credentials(credentials) => Ok(credentials),
+            _ => Err(crate::Error::InvalidState("credentials")),
+        }
+    }
+
+    async fn read_request(&mut self) -> Result<Request, crate::Error> {
+        match self.next_event().await? {
+            ServerEvent::Request(request) => Ok(request),
+            _ => Err(crate::Error::InvalidState("request")),
+        }
+    }
+
+    async fn choose(&mut self, choice: Choice) -> Result<(), Error> {
+        self.server.choose(choice)?;
+        self.flush().await
+    }
+
+    async fn authenticate(&mut self, success: bool) -> Result<(), Error> {
+        self.server.authenticate(success)?;
+        self.flush().await
+    }
+
+    async fn reply(&mut self, response: Response) -> Result<(), Error> {
+        self.server.reply(response)?;
+        self.flush().await
+    }
+
+    /// Answers a request the machine could not parse, which closed the handshake.
+    async fn refuse(&mut self, response: Response) -> Result<(), Error> {
+        self.connection.write_response(response).await
+    }
+
+    /// Writes the bytes the machine has to send to the client.
+    async fn flush(&mut self) -> Result<(), Error> {
+        let output = self.server.take_output();
+        self.connection.write_response(output).await
+    }
+
+    async fn closed(&mut self) -> Error {
+        self.connection.closed().await
+    }
+
+    /// Returns the connection once the handshake is established, with the bytes the client sent
+    /// after it in front of the ones kept from the stream.
+    fn into_connection(self) -> Connection {
+        let mut connection = self.connection;
+        connection.unread(self.server.into_remaining());
+
+        connection
+    }
+}
+
+/// Handles the CONNECT command, relaying data between the client and the target.
+async fn connect(
+    dialer: Arc<dyn Dialer>,
+    config: &Config,
+    context: &Context,
+    mut handshake: Handshake,
+    request: Request,
+    target: Target,
+) {
+    trace!("establishing connection to target");
+    let dial = dialer.dial_with_addr(context, &target);
+    let dialed = match common::timeout(config.connect_timeout, dial, "connection to target").await {
+        Ok(t) => {
+            trace!("successfully connected to target");
+            t
+        }
+        Err(e) => {
+            error!(error = %e, "failed to connect to target");
+
+            if let Err(e) = handshake
+                .reply(Response::new(
+                    Reply::from(&e),
+                    request.addr.to_vec(),
+                    request.port,
+                ))
+                .await
+            {
+                error!(error = ?e, "error writing connection failure response to stream");
+            }
+
+            warn!("connection to target failed, sent failure response");
+
+            return;
+        }
+    };
+    let (stream, target_addr) = dialed;
+
+    let response = Response::new(Reply::RequestGranted, request.addr.to_vec(), request.port);
+
+    if let Err(e) = handshake.reply(response).await {
+        error!(error = ?e, "error writing success response to stream");
+
+        return;
+    }
+
+    trace!("starting data relay between client and target");
+
+    let mut stats = relay::relay_connection(handshake.into_connection(), stream, config).await;
+    stats.target = Some(target);
+    stats.target_addr = target_addr;
+
+    debug!(
+        target = ?stats.target,
+        target_addr = ?stats.target_addr,
+        stats.bytes_to_client,
+        stats.bytes_to_target,
+        stats.packets_to_client,
+        stats.packets_to_target,
+        "relay completed"
+    );
+}
+
+/// Handles the BIND command.
+///
+/// A listener is opened on the address the client is connected to, and its address is sent in
+/// the first reply. Once a peer connects to it, a second reply carrying the peer address is sent
+/// and the data is relayed between the client and that peer.
+async fn bind(
+    handler: Arc<dyn Handler>,
+    config: &Config,
+    context: &Context,
+    mut handshake: Handshake,
+    request: Request,
+) {
+    let listener = match TcpListener::bind(SocketAddr::new(context.local_addr.ip(), 0)).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!(error = %e, "failed to open listener for bind request");
+
+            if let Err(e) = handshake
+                .reply(Response::new(
+                    Reply::GeneralFailure,
+                    request.addr.to_vec(),
+                    request.port,
+                ))
+                .await
+            {
+                error!(error = ?e, "error writing bind failure response to stream");
+            }
+
+            return;
+        }
+    };
+
+    let bound_addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!(error = %e, "failed to get address of bind listener");
+
+            if let Err(e) = handshake
+                .reply(Response::new(
+                    Reply::GeneralFailure,
+                    request.addr.to_vec(),
+                    request.port,
+                ))
+                .await
+            {
+                error!(error = ?e, "error writing bind failure response to stream");
+            }
+
+            return;
+        }
+    };
+
+    debug!(bound_addr = %bound_addr, "listening for incoming connection");
+
+    // NOTE: The first reply is sent after the server creates and binds a new socket.
+    if let Err(e) = handshake
+        .reply(Response::with_addr(Reply::RequestGranted, bound_addr))
+        .await
+    {
+        error!(error = ?e, "error writing first bind response to stream");
+
+        return;
+    }
+
+    // NOTE: The client is not expected to send anything until the second reply, so a read on its
+    // connection ends only when it goes away, and the port is not kept open for it.
+    let accepted = select! {
+        accepted = common::timeout(config.bind_timeout, listener.accept(), "incoming connection") => accepted,
+        e = handshake.closed() => {
+            debug!(error = %e, "client closed connection while waiting for incoming connection");
+
+            return;
+        }
+    };
+
+    let (peer, peer_addr) = match accepted {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            error!(error = %e, "failed to accept incoming connection");
+
+            if let Err(e) = handshake
+                .reply(Response::with_addr(Reply::GeneralFailure, bound_addr))
+                .await
+            {
+                error!(error = ?e, "error writing bind failure response to stream");
+            }
+
+            return;
+        }
+    };
+
+    // NOTE: Only one incoming connection is accepted for each BIND request.
+    drop(listener);
+
+    debug!(incoming_addr = %peer_addr, "incoming connection accepted");
+
+    trace!("processing incoming connection through handler");
+    let reply = match handler.bind(context, request.clone(), peer_addr).await {
+        Ok(r) => {
+            trace!(reply = ?r, "handler approved incoming connection");
+            r
+        }
+        Err(e) => {
+            error!(error = ?e, "handler rejected incoming connection");
+
+            if let Err(e) = handshake
+                .reply(Response::with_addr(
+                    Reply::ConnectionNotAllowedByRuleset,
+                    peer_addr,
+                ))
+                .await
+            {
+                error!(error = ?e, "error writing handler failure response");
+            }
+
+            return;
+        }
+    };
+
+    let granted = reply == Reply::RequestGranted;
+
+    // NOTE: The second reply occurs only after the anticipated incoming connection succeeds or
+    // fails.
+    if let Err(e) = handshake.reply(Response::with_addr(reply, peer_addr)).await {
+        error!(error = ?e, "error writing second bind response to stream");
+
+        return;
+    }
+
+    if !granted {
+        warn!(incoming_addr = %peer_addr, "incoming connection rejected, closing connection");
+
+        return;
+    }
+
+    trace!("starting data relay between client and incoming connection");
+
+    let mut stats = relay::relay_connection(handshake.into_connection(), peer, config).await;
+    stats.target = request.get_target();
+    stats.target_addr = Some(peer_addr);
+
+    debug!(
+        target = ?stats.target,
+        target_addr = ?stats.target_addr,
+        stats.bytes_to_client,
+        stats.bytes_to_target,
+        stats.packets_to_client,
+        stats.packets_to_target,
+        "relay completed"
+    );
+}
+
+/// Handles the UDP ASSOCIATE command.
+///
+/// A UDP socket is opened on the address the client is connected to, and its address is sent in
+/// the reply. Datagrams are relayed through it while the client connection stays open.
+async fn associate(
+    handler: Arc<dyn Handler>,
+    config: &Config,
+    context: &Context,
+    mut handshake: Handshake,
+    request: Request,
+) {
+    let socket = match UdpSocket::bind(SocketAddr::new(context.local_addr.ip(), 0)).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!(error = %e, "failed to open socket for associate request");
+
+            if let Err(e) = handshake
+                .reply(Response::new(
+                    Reply::GeneralFailure,
+                    request.addr.to_vec(),
+                    request.port,
+                ))
+                .await
+            {
+                error!(error = ?e, "error writing associate failure response to stream");
+            }
+
+            return;
+        }
+    };
+
+    let relay_addr = match socket.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!(error = %e, "failed to get address of associate socket");
+
+            if let Err(e) = handshake
+                .reply(Response::new(
+                    Reply::GeneralFailure,
+                    request.addr.to_vec(),
+                    request.port,
+                ))
+                .await
+            {
+                error!(error = ?e, "error writing associate failure response to stream");
+            }
+
+            return;
+        }
+    };
+
+    // NOTE: The client declares the address it is going to send datagrams from. When it does not
+    // know it yet, the address and port are set to zero, so the IP address of the client
+    // connection is used and the port is taken from the first datagram.
+    let client_addr = match request.get_addr() {
+        Some(ip) if !ip.is_unspecified() => SocketAddr::new(ip, request.get_port()),
+        _ => SocketAddr::new(
+            context
+                .peer
+                .addr()
+                .map_or(context.local_addr.ip(), |addr| addr.ip()),
+            request.get_port(),
+        ),
+    };
+
+    debug!(relay_addr = %relay_addr, client_addr = %client_addr, "relaying datagrams for client");
+
+    if let Err(e) = handshake
+        .reply(Response::with_addr(Reply::RequestGranted, relay_addr))
+        .await
+    {
+        error!(error = ?e, "error writing associate response to stream");
+
+        return;
+    }
+
+    trace!("starting datagram relay between client and targets");
+
+    // NOTE: Anything the client sends on the control stream is ignored, including the bytes sent
+    // after the request.
+    let (control, _) = handshake.into_connection().into_parts();
+
+    let stats =
+        relay::relay_datagrams(control, socket, client_addr, handler, context, config).await;
+
+    debug!(
+        destinations = ?stats.destinations,
+        stats.bytes_to_client,
+        stats.bytes_to_target,
+        stats.packets_to_client,
+        stats.packets_to_target,
+        stats.packets_dropped,
+        "datagram relay completed"
+    );
 }
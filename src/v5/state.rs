@@ -0,0 +1,832 @@
+//! Sans-IO state machines of the SOCKS5 handshake.
+//!
+//! The machines do no I/O: the bytes received are fed to them, and they return the events to act
+//! on and the bytes to send, so the protocol can be driven by any event loop.
+//!
+//! The servers of the crate run on them: they feed them the bytes read from a
+//! [`Connection`](crate::common::Connection), write the bytes they return, and await the handler,
+//! the dialer and the timeouts between the events.
+
+use std::mem;
+
+use crate::{
+    common::take_frame,
+    v5::{
+        client::{AuthMethod, Credentials, Greeting, Request},
+        server::{Choice, Response, Status},
+        stream::Auth,
+        Reply,
+    },
+    Command,
+};
+
+/// State of the server side of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerState {
+    /// Waiting for the greeting.
+    Greeting,
+    /// Waiting for [`Server::choose`].
+    Choosing,
+    /// Waiting for the username/password credentials.
+    Credentials,
+    /// Waiting for [`Server::authenticate`].
+    Authenticating,
+    /// Waiting for the request.
+    Request,
+    /// Waiting for [`Server::reply`].
+    Replying,
+    /// Waiting for [`Server::reply`] with the second reply of a BIND request.
+    BindReplying,
+    /// Handshake completed; the data is relayed from now on.
+    Established,
+    /// Handshake failed or rejected; the connection must be closed.
+    Closed,
+}
+
+/// Event of the server side of the handshake.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// The client sent its greeting, to be answered with [`Server::choose`].
+    Greeting(Greeting),
+    /// The client sent its credentials, to be answered with [`Server::authenticate`].
+    Credentials(Credentials),
+    /// The client sent its request, to be answered with [`Server::reply`].
+    Request(Request),
+}
+
+/// Server side of the SOCKS5 handshake.
+///
+/// # Example
+///
+/// ```rust
+/// use socks::v5::{
+///     server::{Choice, Response},
+///     state::{Server, ServerEvent, ServerState},
+///     Reply,
+/// };
+///
+/// let mut server = Server::new();
+///
+/// // Greeting and request sent at once, without waiting for the choice.
+/// server.feed(&[0x05, 0x01, 0x00]);
+/// server.feed(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50]);
+///
+/// assert!(matches!(server.poll_event(), Ok(Some(ServerEvent::Greeting(_)))));
+/// server.choose(Choice::default()).unwrap();
+/// assert_eq!(server.take_output(), vec![0x05, 0x00]);
+///
+/// assert!(matches!(server.poll_event(), Ok(Some(ServerEvent::Request(_)))));
+/// server
+///     .reply(Response::with_addr(Reply::RequestGranted, "127.0.0.1:1080".parse().unwrap()))
+///     .unwrap();
+///
+/// assert_eq!(server.state(), ServerState::Established);
+/// ```
+#[derive(Debug)]
+pub struct Server {
+    state: ServerState,
+    command: Command,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server {
+            state: ServerState::Greeting,
+            command: Command::Invalid,
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> ServerState {
+        self.state
+    }
+
+    /// Feeds bytes received from the client.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+    }
+
+    /// Returns the next event, or `None` when more bytes are needed or an answer to the last
+    /// event is awaited.
+    ///
+    /// Bytes that are not a valid packet close the handshake.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use socks::{
+    ///     v5::state::{Server, ServerState},
+    ///     Error,
+    /// };
+    ///
+    /// let mut server = Server::new();
+    ///
+    /// // SOCKS4 request sent to a SOCKS5 server.
+    /// server.feed(&[0x04, 0x01, 0x00, 0x50, 127, 0, 0, 1, 0x00]);
+    ///
+    /// assert!(matches!(server.poll_event(), Err(Error::UnsupportedVersion(0x04))));
+    /// assert_eq!(server.state(), ServerState::Closed);
+    /// assert!(server.poll_event().unwrap().is_none());
+    /// ```
+    pub fn poll_event(&mut self) -> Result<Option<ServerEvent>, crate::Error> {
+        let event = match self.state {
+            ServerState::Greeting => take_frame(&mut self.input).map(|greeting| {
+                greeting.map(|greeting| (ServerState::Choosing, ServerEvent::Greeting(greeting)))
+            }),
+            ServerState::Credentials => take_frame(&mut self.input).map(|credentials| {
+                credentials.map(|credentials| {
+                    (
+                        ServerState::Authenticating,
+                        ServerEvent::Credentials(credentials),
+                    )
+                })
+            }),
+            ServerState::Request => take_frame::<Request>(&mut self.input).map(|request| {
+                request.map(|request| {
+                    self.command = request.get_command();
+
+                    (ServerState::Replying, ServerEvent::Request(request))
+                })
+            }),
+            _ => Ok(None),
+        };
+
+        match event {
+            Ok(Some((state, event))) => {
+                self.state = state;
+
+                Ok(Some(event))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.state = ServerState::Closed;
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Answers the greeting with the chosen authentication method.
+    ///
+    /// Fails with [`crate::Error::InvalidState`] when no greeting is awaiting an answer.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use socks::{
+    ///     v5::{server::Choice, state::{Server, ServerState}},
+    ///     Error,
+    /// };
+    ///
+    /// let mut server = Server::new();
+    ///
+    /// // The greeting has not been received yet.
+    /// assert!(matches!(server.choose(Choice::default()), Err(Error::InvalidState(_))));
+    /// assert!(server.take_output().is_empty());
+    /// assert_eq!(server.state(), ServerState::Greeting);
+    /// ```
+    pub fn choose(&mut self, choice: Choice) -> Result<(), crate::Error> {
+        if self.state != ServerState::Choosing {
+            return Err(crate::Error::InvalidState("choice"));
+        }
+
+        let method = AuthMethod::from(choice.choose);
+        self.output.extend_from_slice(&<[u8; 2]>::from(choice));
+
+        self.state = match method {
+            AuthMethod::UsernamePassword => ServerState::Credentials,
+            // NOTE: 0xFF means that none of the methods listed by the client are acceptable, and
+            // the client must close the connection.
+            AuthMethod::Unknown => ServerState::Closed,
+            _ => ServerState::Request,
+        };
+
+        Ok(())
+    }
+
+    /// Answers the credentials with whether they were accepted; rejected ones close the
+    /// handshake.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use socks::v5::{
+    ///     server::Choice,
+    ///     state::{Server, ServerEvent, ServerState},
+    /// };
+    ///
+    /// let mut server = Server::new();
+    ///
+    /// server.feed(&[0x05, 0x01, 0x02]);
+    /// assert!(matches!(server.poll_event(), Ok(Some(ServerEvent::Greeting(_)))));
+    /// server.choose(Choice::from([0x05, 0x02])).unwrap();
+    /// assert_eq!(server.state(), ServerState::Credentials);
+    ///
+    /// server.feed(&[0x01, 0x04, b'u', b's', b'e', b'r', 0x04, b'p', b'a', b's', b's']);
+    /// assert!(matches!(server.poll_event(), Ok(Some(ServerEvent::Credentials(_)))));
+    /// server.authenticate(false).unwrap();
+    ///
+    /// assert_eq!(server.take_output(), vec![0x05, 0x02, 0x01, 0x01]);
+    /// assert_eq!(server.state(), ServerState::Closed);
+    /// ```
+    pub fn authenticate(&mut self, success: bool) -> Result<(), crate::Error> {
+        if self.state != ServerState::Authenticating {
+            return Err(crate::Error::InvalidState("authentication status"));
+        }
+
+        self.output
+            .extend_from_slice(&<[u8; 2]>::from(Status::new(success)));
+
+        self.state = if success {
+            ServerState::Request
+        } else {
+            ServerState::Closed
+        };
+
+        Ok(())
+    }
+
+    /// Answers the request; a granted BIND request is answered twice.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use socks::{
+    ///     v5::{
+    ///         server::{Choice, Response},
+    ///         state::{Server, ServerEvent, ServerState},
+    ///         Reply,
+    ///     },
+    ///     Error,
+    /// };
+    ///
+    /// let mut server = Server::new();
+    ///
+    /// server.feed(&[0x05, 0x01, 0x00]);
+    /// server.feed(&[0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0x00, 0x00]);
+    /// assert!(matches!(server.poll_event(), Ok(Some(ServerEvent::Greeting(_)))));
+    /// server.choose(Choice::default()).unwrap();
+    /// assert!(matches!(server.poll_event(), Ok(Some(ServerEvent::Request(_)))));
+    ///
+    /// // First reply, with the address the server listens on.
+    /// server
+    ///     .reply(Response::with_addr(Reply::RequestGranted, "10.0.0.1:4000".parse().unwrap()))
+    ///     .unwrap();
+    /// assert_eq!(server.state(), ServerState::BindReplying);
+    ///
+    /// // Second reply, with the address of the peer that connected.
+    /// server
+    ///     .reply(Response::with_addr(Reply::RequestGranted, "10.0.0.2:5000".parse().unwrap()))
+    ///     .unwrap();
+    /// assert_eq!(server.state(), ServerState::Established);
+    /// assert_eq!(server.take_output().len(), 2 + 10 + 10);
+    ///
+    /// // There is no third reply.
+    /// let response = Response::with_addr(Reply::RequestGranted, "10.0.0.2:5000".parse().unwrap());
+    /// assert!(matches!(server.reply(response), Err(Error::InvalidState(_))));
+    /// ```
+    pub fn reply(&mut self, response: Response) -> Result<(), crate::Error> {
+        let first = match self.state {
+            ServerState::Replying => true,
+            ServerState::BindReplying => false,
+            _ => return Err(crate::Error::InvalidState("reply")),
+        };
+
+        let granted = response.reply == Reply::RequestGranted as u8;
+        self.output.extend(Vec::<u8>::from(response));
+
+        self.state = match (granted, &self.command) {
+            (false, _) => ServerState::Closed,
+            (true, Command::Bind) if first => ServerState::BindReplying,
+            (true, _) => ServerState::Established,
+        };
+
+        Ok(())
+    }
+
+    /// Returns the bytes to send to the client, emptying the output.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.output)
+    }
+
+    /// Returns the bytes received after the handshake, which must be relayed before anything else
+    /// read from the client.
+    pub fn into_remaining(self) -> Vec<u8> {
+        self.input
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+/// State of the client side of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    /// Waiting for the choice of the server.
+    Choice,
+    /// Waiting for the authentication status.
+    Status,
+    /// Waiting for the reply.
+    Reply,
+    /// Waiting for the second reply of a BIND request.
+    BindReply,
+    /// Handshake completed; the data is relayed from now on.
+    Established,
+    /// Handshake failed or rejected; the connection must be closed.
+    Closed,
+}
+
+/// Event of the client side of the handshake.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The server replied to the request.
+    Reply(Response),
+}
+
+/// Client side of the SOCKS5 handshake.
+///
+/// The greeting is in the output from its creation, and the credentials and the request are
+/// added to it as the server answers.
+///
+/// # Example
+///
+/// ```rust
+/// use socks::{
+///     common::Target,
+///     v5::{
+///         client::{Address, Request},
+///         state::{Client, ClientEvent, ClientState},
+///         stream::Auth,
+///     },
+///     Command,
+/// };
+///
+/// let target = Target::from(("example.com", 80));
+/// let request = Request::new(Command::Connect, Address::try_from(&target).unwrap(), 80);
+/// let mut client = Client::new(request, Auth::None);
+///
+/// assert_eq!(client.take_output(), vec![0x05, 0x01, 0x00]);
+///
+/// client.feed(&[0x05, 0x00]);
+/// assert!(client.poll_event().unwrap().is_none());
+/// assert_eq!(client.take_output()[..5], [0x05, 0x01, 0x00, 0x03, 11]);
+///
+/// client.feed(&[0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x04, 0x38]);
+/// assert!(matches!(client.poll_event(), Ok(Some(ClientEvent::Reply(_)))));
+/// assert_eq!(client.state(), ClientState::Established);
+/// ```
+#[derive(Debug)]
+pub struct Client {
+    state: ClientState,
+    request: Option<Request>,
+    command: Command,
+    auth: Auth,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Client {
+    pub fn new(request: Request, auth: Auth) -> Self {
+        let methods = match auth {
+            Auth::None => vec![AuthMethod::NoAuthentication],
+            Auth::UsernamePassword { .. } => {
+                vec![AuthMethod::NoAuthentication, AuthMethod::UsernamePassword]
+            }
+        };
+
+        Client {
+            state: ClientState::Choice,
+            command: request.get_command(),
+            request: Some(request),
+            auth,
+            input: Vec::new(),
+            output: Vec::<u8>::from(Greeting::new(methods)),
+        }
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    /// Feeds bytes received from the server.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+    }
+
+    /// Returns the next event, or `None` when more bytes are needed.
+    ///
+    /// Fails when the server chooses no acceptable authentication method or rejects the
+    /// credentials; a request that is not granted is returned as a reply, and closes the
+    /// handshake.
+    pub fn poll_event(&mut self) -> Result<Option<ClientEvent>, crate::Error> {
+        match self.step() {
+            Ok(event) => Ok(event),
+            Err(e) => {
+                self.state = ClientState::Closed;
+
+                Err(e)
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<Option<ClientEvent>, crate::Error> {
+        loop {
+            match self.state {
+                ClientState::Choice => {
+                    let choice: Choice = match take_frame(&mut self.input)? {
+                        Some(choice) => choice,
+                        None => return Ok(None),
+                    };
+
+                    if choice.version != 0x05 {
+                        return Err(crate::Error::UnsupportedVersion(choice.version));
+                    }
+
+                    match (AuthMethod::from(choice.choose), &self.auth) {
+                        (AuthMethod::NoAuthentication, _) => self.send_request(),
+                        (
+                            AuthMethod::UsernamePassword,
+                            Auth::UsernamePassword { username, password },
+                        ) => {
+                            let credentials = Credentials::new(username, password);
+                            self.output.extend(Vec::<u8>::try_from(credentials)?);
+                            self.state = ClientState::Status;
+                        }
+                        _ => return Err(crate::Error::NoAcceptableAuthMethod),
+                    }
+                }
+                ClientState::Status => {
+                    let status: Status = match take_frame(&mut self.input)? {
+                        Some(status) => status,
+                        None => return Ok(None),
+                    };
+
+                    if status.status != 0x00 {
+                        return Err(crate::Error::AuthenticationFailed);
+                    }
+
+                    self.send_request();
+                }
+                ClientState::Reply | ClientState::BindReply => {
+                    let response: Response = match take_frame(&mut self.input)? {
+                        Some(response) => response,
+                        None => return Ok(None),
+                    };
+
+                    let granted = response.reply == Reply::RequestGranted as u8;
+
+                    self.state = match (granted, &self.command, self.state) {
+                        (false, _, _) => ClientState::Closed,
+                        (true, Command::Bind, ClientState::Reply) => ClientState::BindReply,
+                        (true, _, _) => ClientState::Established,
+                    };
+
+                    return Ok(Some(ClientEvent::Reply(response)));
+                }
+                ClientState::Established | ClientState::Closed => return Ok(None),
+            }
+        }
+    }
+
+    fn send_request(&mut self) {
+        if let Some(request) = self.request.take() {
+            self.output.extend(Vec::<u8>::from(request));
+        }
+
+        self.state = ClientState::Reply;
+    }
+
+    /// Returns the bytes to send to the server, emptying the output.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.output)
+    }
+
+    /// Returns the bytes received after the handshake, which come from the target.
+    pub fn into_remaining(self) -> Vec<u8> {
+        self.input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{Client, ClientEvent, ClientState, Server, ServerEvent, ServerState};
+    use crate::{
+        common::Target,
+        v5::{
+            client::{Address, Request},
+            server::{Choice, Response},
+            stream::Auth,
+            Reply,
+        },
+        Command,
+    };
+
+    const GREETING: [u8; 4] = [0x05, 0x02, 0x00, 0x02];
+    const CREDENTIALS: [u8; 11] = [
+        0x01, 0x04, b'u', b's', b'e', b'r', 0x04, b'p', b'a', b's', b's',
+    ];
+    const CONNECT: [u8; 10] = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50];
+    const GRANTED: [u8; 10] = [0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x04, 0x38];
+
+    fn granted() -> Response {
+        Response::with_addr(
+            Reply::RequestGranted,
+            SocketAddr::from(([10, 0, 0, 1], 1080)),
+        )
+    }
+
+    /// Returns a server that received the greeting and chose the method.
+    fn server(method: u8) -> Server {
+        let mut server = Server::new();
+
+        server.feed(&GREETING);
+        assert!(server.poll_event().unwrap().is_some());
+        server.choose(Choice::from([0x05, method])).unwrap();
+        server.take_output();
+
+        server
+    }
+
+    #[test]
+    fn server_reads_packets_fed_byte_by_byte() {
+        let mut server = Server::new();
+        let bytes = [&GREETING[..], &CREDENTIALS, &CONNECT].concat();
+        let mut events = Vec::new();
+
+        for byte in bytes {
+            server.feed(&[byte]);
+
+            match server.poll_event().unwrap() {
+                Some(ServerEvent::Greeting(greeting)) => {
+                    assert_eq!(greeting.auth, vec![0x00, 0x02]);
+                    server.choose(Choice::from([0x05, 0x02])).unwrap();
+                    events.push("greeting");
+                }
+                Some(ServerEvent::Credentials(credentials)) => {
+                    assert_eq!(credentials.username, "user");
+                    assert_eq!(credentials.password, "pass");
+                    server.authenticate(true).unwrap();
+                    events.push("credentials");
+                }
+                Some(ServerEvent::Request(request)) => {
+                    assert_eq!(request.get_port(), 80);
+                    events.push("request");
+                }
+                None => {}
+            }
+        }
+
+        assert_eq!(events, ["greeting", "credentials", "request"]);
+        assert_eq!(server.state(), ServerState::Replying);
+        assert_eq!(server.take_output(), vec![0x05, 0x02, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn server_waits_for_the_answer_to_each_event() {
+        let mut server = Server::new();
+
+        server.feed(&[&GREETING[..], &CONNECT].concat());
+
+        assert!(server.poll_event().unwrap().is_some());
+        assert_eq!(server.state(), ServerState::Choosing);
+
+        // NOTE: The request is not read before the method is chosen.
+        assert!(server.poll_event().unwrap().is_none());
+        assert!(server.authenticate(true).is_err());
+        assert!(server.reply(granted()).is_err());
+
+        server.choose(Choice::default()).unwrap();
+        assert!(matches!(
+            server.poll_event(),
+            Ok(Some(ServerEvent::Request(_)))
+        ));
+    }
+
+    #[test]
+    fn server_rejects_invalid_greetings() {
+        let mut server = Server::new();
+
+        server.feed(&[0x04, 0x01, 0x00]);
+
+        assert!(matches!(
+            server.poll_event(),
+            Err(crate::Error::UnsupportedVersion(0x04))
+        ));
+        assert_eq!(server.state(), ServerState::Closed);
+        assert!(server.choose(Choice::default()).is_err());
+    }
+
+    #[test]
+    fn server_closes_when_no_method_is_acceptable() {
+        let mut server = Server::new();
+
+        server.feed(&GREETING);
+        assert!(server.poll_event().unwrap().is_some());
+        server.choose(Choice::from([0x05, 0xFF])).unwrap();
+
+        assert_eq!(server.take_output(), vec![0x05, 0xFF]);
+        assert_eq!(server.state(), ServerState::Closed);
+    }
+
+    #[test]
+    fn server_rejects_invalid_credentials() {
+        let mut server = server(0x02);
+
+        server.feed(&[0x02, 0x01, b'u', 0x01, b'p']);
+
+        assert!(matches!(
+            server.poll_event(),
+            Err(crate::Error::UnsupportedVersion(0x02))
+        ));
+        assert_eq!(server.state(), ServerState::Closed);
+        assert!(server.authenticate(true).is_err());
+    }
+
+    #[test]
+    fn server_closes_on_rejected_credentials() {
+        let mut server = server(0x02);
+
+        server.feed(&[&CREDENTIALS[..], &CONNECT].concat());
+        assert!(server.poll_event().unwrap().is_some());
+        server.authenticate(false).unwrap();
+
+        assert_eq!(server.take_output(), vec![0x01, 0x01]);
+        assert_eq!(server.state(), ServerState::Closed);
+        assert!(server.poll_event().unwrap().is_none());
+    }
+
+    #[test]
+    fn server_rejects_invalid_requests() {
+        let cases: [(&[u8], crate::Error); 3] = [
+            (
+                &[0x04, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50],
+                crate::Error::UnsupportedVersion(0x04),
+            ),
+            (
+                &[0x05, 0x09, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50],
+                crate::Error::UnsupportedCommand(0x09),
+            ),
+            (
+                &[0x05, 0x01, 0x00, 0x09, 127, 0, 0, 1, 0x00, 0x50],
+                crate::Error::UnsupportedAddressType(0x09),
+            ),
+        ];
+
+        for (bytes, expected) in cases {
+            let mut server = server(0x00);
+            server.feed(bytes);
+
+            let error = server.poll_event().unwrap_err();
+            assert_eq!(error.to_string(), expected.to_string());
+            assert_eq!(server.state(), ServerState::Closed);
+            assert!(server.reply(granted()).is_err());
+        }
+    }
+
+    #[test]
+    fn server_replies_and_keeps_the_bytes_after_the_request() {
+        let mut server = server(0x00);
+
+        server.feed(&[&CONNECT[..], b"data"].concat());
+        assert!(server.poll_event().unwrap().is_some());
+        assert!(server.poll_event().unwrap().is_none());
+
+        server.reply(granted()).unwrap();
+        assert_eq!(server.state(), ServerState::Established);
+        assert_eq!(server.take_output(), GRANTED);
+        assert!(server.reply(granted()).is_err());
+
+        assert_eq!(server.into_remaining(), b"data");
+    }
+
+    #[test]
+    fn server_closes_on_a_rejected_request() {
+        let mut server = server(0x00);
+
+        server.feed(&CONNECT);
+        assert!(server.poll_event().unwrap().is_some());
+
+        server
+            .reply(Response::with_addr(
+                Reply::ConnectionNotAllowedByRuleset,
+                SocketAddr::from(([0, 0, 0, 0], 0)),
+            ))
+            .unwrap();
+        assert_eq!(server.state(), ServerState::Closed);
+        assert_eq!(server.take_output()[1], 0x02);
+    }
+
+    fn client(command: Command, auth: Auth) -> Client {
+        let target = Target::from(SocketAddr::from(([10, 0, 0, 2], 80)));
+        let request = Request::new(command, Address::try_from(&target).unwrap(), 80);
+
+        let mut client = Client::new(request, auth);
+        client.take_output();
+
+        client
+    }
+
+    fn credentials() -> Auth {
+        Auth::UsernamePassword {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        }
+    }
+
+    #[test]
+    fn client_authenticates_with_the_chosen_method() {
+        let mut client = client(Command::Connect, credentials());
+
+        client.feed(&[0x05]);
+        assert!(client.poll_event().unwrap().is_none());
+        client.feed(&[0x02]);
+        assert!(client.poll_event().unwrap().is_none());
+        assert_eq!(client.state(), ClientState::Status);
+        assert_eq!(client.take_output(), CREDENTIALS);
+
+        client.feed(&[0x01, 0x00]);
+        assert!(client.poll_event().unwrap().is_none());
+        assert_eq!(client.state(), ClientState::Reply);
+        assert_eq!(client.take_output()[..2], [0x05, 0x01]);
+
+        client.feed(&[&GRANTED[..], b"data"].concat());
+        let Some(ClientEvent::Reply(response)) = client.poll_event().unwrap() else {
+            panic!("expected a reply");
+        };
+
+        assert_eq!(response.get_port(), 1080);
+        assert_eq!(client.state(), ClientState::Established);
+        assert_eq!(client.into_remaining(), b"data");
+    }
+
+    #[test]
+    fn client_rejects_invalid_choices() {
+        let cases: [(&[u8], crate::Error); 3] = [
+            (&[0x04, 0x00], crate::Error::UnsupportedVersion(0x04)),
+            (&[0x05, 0xFF], crate::Error::NoAcceptableAuthMethod),
+            // NOTE: The server chose a method the client did not offer.
+            (&[0x05, 0x02], crate::Error::NoAcceptableAuthMethod),
+        ];
+
+        for (bytes, expected) in cases {
+            let mut client = client(Command::Connect, Auth::None);
+            client.feed(bytes);
+
+            let error = client.poll_event().unwrap_err();
+            assert_eq!(error.to_string(), expected.to_string());
+            assert_eq!(client.state(), ClientState::Closed);
+            assert!(client.take_output().is_empty());
+        }
+    }
+
+    #[test]
+    fn client_fails_on_rejected_credentials() {
+        let mut client = client(Command::Connect, credentials());
+
+        client.feed(&[0x05, 0x02, 0x01, 0x01]);
+
+        assert!(matches!(
+            client.poll_event(),
+            Err(crate::Error::AuthenticationFailed)
+        ));
+        assert_eq!(client.state(), ClientState::Closed);
+    }
+
+    #[test]
+    fn client_reads_both_bind_replies() {
+        let mut client = client(Command::Bind, Auth::None);
+
+        client.feed(&[&[0x05, 0x00][..], &GRANTED, &GRANTED].concat());
+
+        assert!(client.poll_event().unwrap().is_some());
+        assert_eq!(client.state(), ClientState::BindReply);
+        assert!(client.poll_event().unwrap().is_some());
+        assert_eq!(client.state(), ClientState::Established);
+    }
+
+    #[test]
+    fn client_closes_on_a_rejected_request() {
+        let mut client = client(Command::Connect, Auth::None);
+
+        client.feed(&[0x05, 0x00, 0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+
+        let Some(ClientEvent::Reply(response)) = client.poll_event().unwrap() else {
+            panic!("expected a reply");
+        };
+
+        assert_eq!(
+            response.reply,
+            Reply::ConnectionRefusedByDestinationHost as u8
+        );
+        assert_eq!(client.state(), ClientState::Closed);
+        assert!(client.poll_event().unwrap().is_none());
+    }
+}
@@ -0,0 +1,279 @@
+//! SOCKS5 client stream, connected to a target through a SOCKS5 proxy server.
+
+use std::{
+    io::{Error, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, ToSocketAddrs},
+};
+#[cfg(feature = "tls")]
+use tokio_rustls::{client::TlsStream, rustls::pki_types::ServerName, TlsConnector};
+use tracing::{debug, trace};
+
+use crate::{common::Target, Command, ReplyError};
+
+use super::{
+    client::{Address, Request},
+    state::{Client, ClientEvent},
+    Reply,
+};
+
+/// Authentication method used to connect to a SOCKS5 proxy server.
+#[derive(Debug, Clone)]
+pub enum Auth {
+    /// No authentication.
+    None,
+    /// Username/password authentication, as defined by RFC 1929.
+    UsernamePassword { username: String, password: String },
+}
+
+/// Stream connected to a target through a SOCKS5 proxy server.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use socks::v5::stream::Socks5Stream;
+/// use tokio::io::AsyncWriteExt;
+///
+/// # async fn run() -> Result<(), std::io::Error> {
+/// let mut stream = Socks5Stream::connect("127.0.0.1:1080", ("example.com", 80)).await?;
+/// stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Socks5Stream<S = TcpStream> {
+    stream: S,
+    bound_addr: Target,
+    /// Bytes from the target received along with the reply, returned before reading the stream.
+    pending: Vec<u8>,
+}
+
+impl Socks5Stream<TcpStream> {
+    /// Connects to the target through the proxy, without authentication.
+    pub async fn connect(
+        proxy: impl ToSocketAddrs,
+        target: impl Into<Target>,
+    ) -> Result<Self, Error> {
+        let stream = TcpStream::connect(proxy).await?;
+
+        Socks5Stream::connect_with(stream, target, Auth::None).await
+    }
+
+    /// Connects to the target through the proxy, authenticating with username and password.
+    pub async fn connect_with_password(
+        proxy: impl ToSocketAddrs,
+        target: impl Into<Target>,
+        username: &str,
+        password: &str,
+    ) -> Result<Self, Error> {
+        let stream = TcpStream::connect(proxy).await?;
+
+        Socks5Stream::connect_with(
+            stream,
+            target,
+            Auth::UsernamePassword {
+                username: username.to_string(),
+                password: password.to_string(),
+            },
+        )
+        .await
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Socks5Stream<TlsStream<TcpStream>> {
+    /// Connects to the target through a proxy serving SOCKS5 over TLS, verifying that its
+    /// certificate is valid for the server name.
+    ///
+    /// The connector is built from a configuration such as the ones of
+    /// [`client_config`](crate::common::tls::client_config) and
+    /// [`client_config_with_certificate`](crate::common::tls::client_config_with_certificate).
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::sync::Arc;
+    ///
+    /// use socks::{
+    ///     common::tls::{self, TlsConnector},
+    ///     v5::stream::{Auth, Socks5Stream},
+    /// };
+    ///
+    /// # async fn run() -> Result<(), std::io::Error> {
+    /// let connector = TlsConnector::from(Arc::new(tls::client_config("ca.crt")?));
+    ///
+    /// let stream = Socks5Stream::connect_tls(
+    ///     "proxy.example.com:1080",
+    ///     &connector,
+    ///     "proxy.example.com",
+    ///     ("example.com", 80),
+    ///     Auth::UsernamePassword {
+    ///         username: "user".to_string(),
+    ///         password: "secret".to_string(),
+    ///     },
+    /// )
+    /// .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn connect_tls(
+        proxy: impl ToSocketAddrs,
+        connector: &TlsConnector,
+        server_name: &str,
+        target: impl Into<Target>,
+        auth: Auth,
+    ) -> Result<Self, Error> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        let stream = TcpStream::connect(proxy).await?;
+        let stream = connector.connect(server_name, stream).await?;
+
+        trace!("TLS handshake with proxy completed");
+
+        Socks5Stream::connect_with(stream, target, auth).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Stream<S> {
+    /// Connects to the target through a stream already connected to the proxy.
+    pub async fn connect_with(
+        mut stream: S,
+        target: impl Into<Target>,
+        auth: Auth,
+    ) -> Result<Self, Error> {
+        let target = target.into();
+        debug!(target = %target, "connecting to target through proxy");
+
+        if let Auth::UsernamePassword { username, password } = &auth {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "username or password too long",
+                ));
+            }
+        }
+
+        let request = Request::new(Command::Connect, Address::try_from(&target)?, target.port());
+        let mut client = Client::new(request, auth);
+
+        let response = loop {
+            if let Some(ClientEvent::Reply(response)) = client.poll_event()? {
+                break response;
+            }
+
+            let output = client.take_output();
+            if !output.is_empty() {
+                trace!(state = ?client.state(), "sending handshake to proxy");
+                stream.write_all(&output).await?;
+            }
+
+            let mut buffer = [0u8; 512];
+            let size = stream.read(&mut buffer).await?;
+            if size == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "proxy closed connection",
+                ));
+            }
+
+            client.feed(&buffer[..size]);
+        };
+
+        // NOTE: Unknown reply codes are treated as general failures.
+        let reply = response.get_reply().unwrap_or(Reply::GeneralFailure);
+
+        if reply != Reply::RequestGranted {
+            return Err(reply_error(reply));
+        }
+
+        let bound_addr = Address::try_from(response.ip.clone())?
+            .get_target(response.get_port())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid bound address"))?;
+
+        debug!(bound_addr = %bound_addr, "connected to target through proxy");
+
+        Ok(Socks5Stream {
+            stream,
+            bound_addr,
+            pending: client.into_remaining(),
+        })
+    }
+
+    /// Returns the address the proxy bound to connect to the target.
+    pub fn bound_addr(&self) -> &Target {
+        &self.bound_addr
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Returns the stream to the proxy, discarding any bytes from the target received along with
+    /// the reply and not read yet.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+/// Converts a reply that is not granted into an error.
+fn reply_error(reply: Reply) -> Error {
+    let kind = match reply {
+        Reply::ConnectionNotAllowedByRuleset => ErrorKind::PermissionDenied,
+        Reply::NetworkUnreachable => ErrorKind::NetworkUnreachable,
+        Reply::HostUnreachable => ErrorKind::HostUnreachable,
+        Reply::ConnectionRefusedByDestinationHost => ErrorKind::ConnectionRefused,
+        Reply::TtlExpired => ErrorKind::TimedOut,
+        Reply::CommandNotSupportedOrProtocolError | Reply::AddressTypeNotSupported => {
+            ErrorKind::Unsupported
+        }
+        Reply::RequestGranted | Reply::GeneralFailure => ErrorKind::Other,
+    };
+
+    Error::new(kind, ReplyError::V5(reply))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Socks5Stream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        if !self.pending.is_empty() {
+            let size = self.pending.len().min(buf.remaining());
+            buf.put_slice(&self.pending[..size]);
+            self.pending.drain(..size);
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Socks5Stream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
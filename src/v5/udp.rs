@@ -0,0 +1,101 @@
+//! SOCKS5 UDP request header, and utilities.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::common::{Destination, Target};
+
+use super::client::{Address, Kind};
+
+/// SOCKS5 UDP datagram.
+///
+/// Each datagram exchanged between the client and the UDP relay server carries this header in
+/// front of its data.
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    /// Reserved.
+    pub rsv: [u8; 2],
+    /// Current fragment number.
+    pub frag: u8,
+    /// Destination address with its type.
+    pub addr: Vec<u8>,
+    /// Destination port number.
+    pub port: [u8; 2],
+    /// User data.
+    pub data: Vec<u8>,
+}
+
+impl Datagram {
+    /// Creates a datagram carrying data received from, or sent to, the given address.
+    pub fn new(addr: SocketAddr, data: Vec<u8>) -> Self {
+        Datagram {
+            rsv: [0x00, 0x00],
+            frag: 0x00,
+            addr: Address::from(addr).into(),
+            port: addr.port().to_be_bytes(),
+            data,
+        }
+    }
+
+    /// Returns true if the datagram is a fragment, which is not supported.
+    pub fn is_fragment(&self) -> bool {
+        self.frag != 0x00
+    }
+
+    /// Returns the IP address of the destination, or `None` when it is a domain name.
+    pub fn get_addr(&self) -> Option<IpAddr> {
+        Address::try_from(self.addr.clone()).ok()?.get_addr()
+    }
+
+    /// Returns the destination host, without resolving its domain name.
+    pub fn get_destination(&self) -> Option<Destination> {
+        Address::try_from(self.addr.clone()).ok()?.get_destination()
+    }
+
+    /// Returns the destination, without resolving its domain name.
+    pub fn get_target(&self) -> Option<Target> {
+        Address::try_from(self.addr.clone())
+            .ok()?
+            .get_target(self.get_port())
+    }
+
+    pub fn get_port(&self) -> u16 {
+        u16::from_be_bytes(self.port)
+    }
+}
+
+impl TryFrom<&[u8]> for Datagram {
+    type Error = crate::Error;
+
+    fn try_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        if buffer.len() < 4 {
+            return Err(crate::Error::Malformed("datagram too short"));
+        }
+
+        let addr_length =
+            Kind::addr_len(&buffer[3..])?.ok_or(crate::Error::Malformed("datagram too short"))?;
+
+        let port_position = 4 + addr_length;
+        if buffer.len() < port_position + 2 {
+            return Err(crate::Error::Malformed("datagram too short"));
+        }
+
+        Ok(Datagram {
+            rsv: [buffer[0], buffer[1]],
+            frag: buffer[2],
+            addr: Vec::from(&buffer[3..port_position]),
+            port: [buffer[port_position], buffer[port_position + 1]],
+            data: Vec::from(&buffer[port_position + 2..]),
+        })
+    }
+}
+
+impl From<Datagram> for Vec<u8> {
+    fn from(datagram: Datagram) -> Self {
+        let mut buffer = vec![datagram.rsv[0], datagram.rsv[1], datagram.frag];
+        buffer.extend_from_slice(&datagram.addr);
+        buffer.extend_from_slice(&datagram.port);
+        buffer.extend_from_slice(&datagram.data);
+
+        buffer
+    }
+}